@@ -4,6 +4,24 @@
 with lib;
 let
   cfg = config.services.icloud-biff;
+  quietHours = types.submodule {
+    options = {
+      start = mkOption {
+        type = types.str;
+        example = "22:00";
+        description = ''
+          Start of the quiet period (HH:MM, local time).
+        '';
+      };
+      end = mkOption {
+        type = types.str;
+        example = "07:00";
+        description = ''
+          End of the quiet period (HH:MM, local time).
+        '';
+      };
+    };
+  };
 in
 {
   #
@@ -39,11 +57,79 @@ in
     };
 
     recipient-email-addrs = mkOption {
-      type = types.listOf types.str;
+      type = types.listOf (types.either types.str (types.submodule {
+        options = {
+          addr = mkOption {
+            type = types.str;
+            description = ''
+              Email address of the recipient.
+            '';
+          };
+          time-zone = mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            example = "America/New_York";
+            description = ''
+              IANA time zone of the recipient, if different to time-zone.
+            '';
+          };
+          quiet-hours = mkOption {
+            type = types.nullOr quietHours;
+            default = null;
+            description = ''
+              Quiet hours for this recipient, if different to quiet-hours.
+            '';
+          };
+          digest = mkOption {
+            default = null;
+            description = ''
+              If set, batch up updates and send them on this schedule, rather
+              than straight away.
+            '';
+            type = types.nullOr (types.submodule {
+              options = {
+                day = mkOption {
+                  type = types.nullOr types.str;
+                  default = null;
+                  example = "sunday";
+                  description = ''
+                    Day of the week for a weekly digest (or null for daily).
+                  '';
+                };
+                time = mkOption {
+                  type = types.str;
+                  example = "18:00";
+                  description = ''
+                    Local time of day (HH:MM) to send the digest.
+                  '';
+                };
+              };
+            });
+          };
+          pgp-key-file = mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            description = ''
+              File containing the recipient's OpenPGP public key, to send them
+              PGP/MIME encrypted emails.
+            '';
+          };
+          smime-cert-file = mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            description = ''
+              File containing the recipient's S/MIME certificate (PEM), to
+              send them S/MIME encrypted emails.
+            '';
+          };
+        };
+      }));
       example = "[ mum@example.com friend@example.com ]";
       description = ''
         List of email recipients. This should just be the pure email address,
         eg "mum@example.com" is good, but "My Mum <mum@example.com>" is bad.
+        An entry can also be an attribute set with the address as "addr", plus
+        options for that recipient.
       '';
     };
 
@@ -62,6 +148,186 @@ in
         Human-readable name to use as the From: address when sending email.
       '';
     };
+
+    delivery = mkOption {
+      type = types.enum [ "shared" "bcc" "individual" ];
+      default = "shared";
+      description = ''
+        How to address emails: "shared" lists all recipients in To:, "bcc"
+        hides them from each other, and "individual" sends each recipient a
+        separate copy (so one bad address can't affect the others).
+      '';
+    };
+
+    dkim = mkOption {
+      default = null;
+      description = ''
+        Optionally DKIM-sign outgoing emails.
+      '';
+      type = types.nullOr (types.submodule {
+        options = {
+          domain = mkOption {
+            type = types.str;
+            example = "example.com";
+            description = ''
+              Signing domain (usually that of sender-email-addr).
+            '';
+          };
+          selector = mkOption {
+            type = types.str;
+            example = "icloud-biff";
+            description = ''
+              Selector under which the public key is published in DNS.
+            '';
+          };
+          private-key-file = mkOption {
+            type = types.str;
+            example = "/run/secrets/icloud-biff-dkim.pem";
+            description = ''
+              PEM file containing the RSA or Ed25519 private key (readable by
+              the icloud-biff user). A path as a string, so the key isn't
+              copied into the Nix store.
+            '';
+          };
+        };
+      });
+    };
+
+    unsubscribe = mkOption {
+      default = null;
+      description = ''
+        Optionally add one-click unsubscribe links to each email (requires
+        delivery = "individual"), and run a small HTTP server to handle them.
+      '';
+      type = types.nullOr (types.submodule {
+        options = {
+          base-url = mkOption {
+            type = types.str;
+            example = "https://example.com/photos";
+            description = ''
+              Public URL that is reverse-proxied to the unsubscribe server.
+            '';
+          };
+          secret-file = mkOption {
+            type = types.str;
+            example = "/run/secrets/icloud-biff-unsubscribe";
+            description = ''
+              File containing the secret key used to sign unsubscribe links
+              (readable by the icloud-biff user). A path as a string, so the
+              secret isn't copied into the Nix store.
+            '';
+          };
+          listen-addr = mkOption {
+            type = types.str;
+            default = "127.0.0.1:8025";
+            description = ''
+              Address for the unsubscribe server to listen on.
+            '';
+          };
+        };
+      });
+    };
+
+    time-zone = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "Europe/London";
+      description = ''
+        IANA time zone for interpreting times of day (defaults to the system
+        time zone).
+      '';
+    };
+
+    quiet-hours = mkOption {
+      type = types.nullOr quietHours;
+      default = null;
+      description = ''
+        Daily period during which no emails are sent: anything new is held
+        back until the end.
+      '';
+    };
+
+    settle-minutes = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      example = 30;
+      description = ''
+        Wait until this long after the most recent upload before emailing, so
+        that photos added over a few minutes end up in one email.
+      '';
+    };
+
+    max-thumbnails = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      example = 50;
+      description = ''
+        Maximum number of thumbnails to show in one email (any others are
+        summarised with a count and a link to the album).
+      '';
+    };
+
+    thumbnail-size = mkOption {
+      type = types.ints.between 1 65535;
+      default = 200;
+      description = ''
+        Size (in px, on the long edge) to show thumbnails at. High-DPI screens
+        get a sharper version, where iCloud has one.
+      '';
+    };
+
+    contact-sheet = mkOption {
+      default = null;
+      description = ''
+        If set, download the thumbnails and attach them to the email as a
+        single contact sheet image, rather than linking to each one.
+      '';
+      type = types.nullOr (types.submodule {
+        options = {
+          columns = mkOption {
+            type = types.ints.positive;
+            default = 4;
+            description = "Number of thumbnails across the contact sheet.";
+          };
+          captions = mkOption {
+            type = types.bool;
+            default = false;
+            description = "Whether to show captions under the thumbnails.";
+          };
+        };
+      });
+    };
+
+    thumbnail-selection = mkOption {
+      type = types.enum [ "first" "even" "fair-share" ];
+      default = "first";
+      description = ''
+        How to choose which thumbnails to show when there are more than
+        max-thumbnails: the first ones, a sample spread evenly across the new
+        ones, or an equal share per contributor.
+      '';
+    };
+
+    notifiers = mkOption {
+      type = types.listOf (types.attrsOf types.anything);
+      default = [ ];
+      example = "[ { type = \"webhook\"; url = \"https://example.com/hooks/photos\"; } ]";
+      description = ''
+        Other services to tell about each update straight away, as well as
+        emailing. Each has a "type" ("webhook", "matrix", "telegram", "ntfy",
+        "gotify", "atom", "exec", "webdav" or "s3"), plus options for that
+        type: see the README.
+      '';
+    };
+
+    threading = mkOption {
+      type = types.bool;
+      default = true;
+      description = ''
+        Add Message-ID/In-Reply-To/References headers so that mail clients
+        thread all the update emails for an album into one conversation.
+      '';
+    };
   };
 
   #
@@ -72,6 +338,8 @@ in
     let
       config-file-contents = cfg // {
         sendmail-path = "/run/wrappers/bin/sendmail";
+        gpg-path = "${pkgs.gnupg}/bin/gpg";
+        openssl-path = "${pkgs.openssl}/bin/openssl";
         db-file = "/var/lib/icloud-biff/seen-gids.json";
       };
       config-file = pkgs.writeText "icloud-biff-config.json" (builtins.toJSON config-file-contents);
@@ -95,6 +363,19 @@ in
         };
       };
 
+      # Unsubscribe server, if required
+      systemd.services.icloud-biff-unsubscribe = mkIf (cfg.unsubscribe != null) {
+        description = "Handle icloud-biff unsubscribe requests";
+        wantedBy = [ "multi-user.target" ];
+        after = [ "network.target" ];
+        serviceConfig = {
+          ExecStart = "${pkgs.icloud-biff}/bin/icloud-biff --config ${config-file} serve-unsubscribe";
+          User = "icloud-biff";
+          Group = "icloud-biff";
+          Restart = "on-failure";
+        };
+      };
+
       # User/group
       users = {
         users.icloud-biff = {
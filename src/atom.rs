@@ -0,0 +1,185 @@
+//! Atom feed output (RFC 4287): keep a feed file of recent updates
+//!
+//! Each update becomes an entry, with the same HTML content as the email. The
+//! entries are kept in the state file (rather than parsed back out of the
+//! feed), and the whole feed is rewritten from them each time.
+
+use crate::html;
+use crate::notify::{Notifier, Update};
+use crate::schedule;
+use crate::select;
+use crate::state::{FeedEntry, State};
+use crate::types::*;
+use crate::utils::escape_html;
+use chrono::{DateTime, SecondsFormat, Utc};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+
+/// A configured Atom feed.
+#[derive(Debug)]
+pub struct Atom<'a> {
+    config: &'a Config,
+    atom: &'a AtomConfig,
+}
+
+impl<'a> Atom<'a> {
+    /// Set up an Atom feed.
+    pub fn new(config: &'a Config, atom: &'a AtomConfig) -> Self {
+        Atom { config, atom }
+    }
+
+    /// Write out the feed, via a temporary file so readers never see half of
+    /// it.
+    fn write(&self, entries: &[FeedEntry]) -> Result<(), std::io::Error> {
+        let tmp_file = format!("{}.tmp", self.atom.file);
+        fs::write(&tmp_file, render(self.config, entries))?;
+        fs::rename(&tmp_file, &self.atom.file)
+    }
+}
+
+impl Notifier for Atom<'_> {
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Disappearing assets aren't news.
+        if update.new_assets.is_empty() {
+            return Ok(());
+        }
+
+        // Render the content as for an email (but only showing thumbnails that
+        // could be fetched).
+        let assets = update.assets();
+        let with_thumbnails: Vec<&Asset> = assets
+            .iter()
+            .copied()
+            .filter(|asset| update.thumbnail_urls.contains_key(&asset.checksum))
+            .collect();
+        let content = html::fragment(
+            self.config,
+            &assets,
+            select::thumbnails(self.config, &with_thumbnails),
+            update.thumbnail_urls,
+            schedule::default_time_zone(self.config),
+            None,
+        );
+
+        let mut authors: Vec<String> = vec![];
+        for asset in &assets {
+            if !asset.contributor.is_empty() && !authors.contains(&asset.contributor) {
+                authors.push(asset.contributor.clone());
+            }
+        }
+        let title = format!(
+            "{} added {}",
+            match authors.as_slice() {
+                [] => "Someone".to_string(),
+                authors => authors.join(", "),
+            },
+            html::counts(&assets)
+        );
+        let guids: Vec<String> = assets.iter().map(|a| a.guid.to_string()).collect();
+        let entry = FeedEntry {
+            id: urn(&self.config.album_id, &guids),
+            title,
+            updated: Utc::now(),
+            authors,
+            content,
+        };
+
+        // Add the entry (replacing any previous version, if the same update
+        // is seen again after a failed run).
+        let entries = state.feeds.entry(self.atom.file.clone()).or_default();
+        entries.retain(|old| old.id != entry.id);
+        entries.insert(0, entry);
+        entries.truncate(self.atom.max_entries);
+        self.write(entries)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Atom<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Atom feed {}", self.atom.file)
+    }
+}
+
+/// A stable ID for something in an album, identified by some `parts`: a
+/// name-based UUID (RFC 9562 version 8, from SHA-256), as a URN.
+fn urn<S: AsRef<str>>(album_id: &AlbumId, parts: &[S]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(album_id.to_string());
+    for part in parts {
+        hasher.update("\n");
+        hasher.update(part.as_ref());
+    }
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&hasher.finalize()[..16]);
+    uuid[6] = (uuid[6] & 0x0f) | 0x80; // version 8
+    uuid[8] = (uuid[8] & 0x3f) | 0x80; // RFC 9562 variant
+    let hex = hex::encode(uuid);
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Render the whole feed, with the `entries` newest first.
+fn render(config: &Config, entries: &[FeedEntry]) -> String {
+    let album_url = escape_html(&config.album_id.url());
+    let timestamp =
+        |time: &DateTime<Utc>| time.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let updated = entries
+        .iter()
+        .map(|entry| entry.updated)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         \x20 <id>{}</id>\n\
+         \x20 <title>{}</title>\n\
+         \x20 <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n\
+         \x20 <updated>{}</updated>\n\
+         \x20 <author><name>{}</name></author>\n\
+         \x20 <generator>icloud-biff</generator>\n",
+        urn(&config.album_id, &["feed"]),
+        escape_html(&config.album_name),
+        album_url,
+        timestamp(&updated),
+        escape_html(&config.sender_email_name),
+    );
+    for entry in entries {
+        let authors: String = entry
+            .authors
+            .iter()
+            .map(|name| {
+                format!("    <author><name>{}</name></author>\n", escape_html(name))
+            })
+            .collect();
+        xml += &format!(
+            "  <entry>\n\
+             \x20   <id>{}</id>\n\
+             \x20   <title>{}</title>\n\
+             \x20   <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n\
+             \x20   <updated>{}</updated>\n\
+             {}\
+             \x20   <content type=\"html\">{}</content>\n\
+             \x20 </entry>\n",
+            entry.id,
+            escape_html(&entry.title),
+            album_url,
+            timestamp(&entry.updated),
+            authors,
+            escape_html(&entry.content),
+        );
+    }
+    xml + "</feed>\n"
+}
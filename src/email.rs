@@ -1,58 +1,370 @@
 //! Send email
 
+use crate::dkim;
+use crate::encrypt;
+use crate::sheet::{self, ContactSheet};
+use crate::state::{Deliveries, Delivery, State};
 use crate::types::*;
+use crate::unsubscribe;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Utc;
 use lettre::sendmail::SendmailTransport;
-use lettre::Transport;
-use lettre_email::{EmailBuilder, MimeMultipartType, PartBuilder};
-
-/// Dispatch the provided HTML email
-pub fn send(config: &Config, html: String) -> Result<(), Box<dyn std::error::Error>> {
-    // Dynamic fields other than html body
-    let plaintext = format!(
-        "New {} photos are available at {}",
-        config.album_name,
-        config.album_id.url()
-    );
-    let subject = format!("New {} photos", config.album_name);
-
-    // Construct email
-    let email =
-        // We need to fold over the vector of recipients, to update the builder
-        // value with each one...
-        (config.recipient_email_addrs)
+use lettre::{SendableEmail, Transport};
+use lettre_email::{EmailBuilder, Header, MimeMessage, MimeMultipartType, PartBuilder};
+use sha2::{Digest, Sha256};
+
+/// The subject line for update emails (which other notifiers use as a title
+/// too).
+pub fn subject(config: &Config) -> String {
+    format!("New {} photos", config.album_name)
+}
+
+/// Warn about any recipients who can't get unsubscribe links, as they share an
+/// email. (That's down to the config, rather than anything going wrong with a
+/// run, so it isn't reported as a run warning.)
+pub fn check_config(config: &Config) {
+    if config.unsubscribe.is_none() || config.delivery == DeliveryMode::Individual {
+        return;
+    }
+    let shared: Vec<&str> = config
+        .recipient_email_addrs
+        .iter()
+        .filter(|r| r.encryption.is_none())
+        .map(|r| r.addr.as_str())
+        .collect();
+    if !shared.is_empty() {
+        eprintln!(
+            "Warning: unsubscribe links need delivery = \"individual\", so aren't in the email to {}",
+            shared.join(", ")
+        );
+    }
+}
+
+/// Dispatch the provided HTML (and plain text) email to the specified recipients (other than
+/// any who have unsubscribed), according to the configured delivery mode, and
+/// update the threading state if enabled.
+///
+/// If there's a `contact_sheet`, it's attached for the HTML to refer to.
+///
+/// The outcome for each recipient is returned: this only fails outright if
+/// nobody at all could be sent the email.
+pub fn send(
+    config: &Config,
+    recipients: &[&Recipient],
+    html: String,
+    plaintext: String,
+    contact_sheet: Option<&ContactSheet>,
+    state: &mut State,
+) -> Result<Deliveries, Box<dyn std::error::Error>> {
+    // Construct the parts of the email common to all recipients
+    let builder = EmailBuilder::new()
+        .from((
+            config.sender_email_addr.clone(),
+            config.sender_email_name.clone(),
+        ))
+        .subject(subject(config))
+        .header(("List-Id", list_id(config)))
+        .alternative_body(html, plaintext, contact_sheet);
+
+    // Address the email: either as one message, or one per recipient. Anyone
+    // whose email is encrypted always gets their own copy.
+    let recipients: Vec<&Recipient> = recipients
         .iter()
-        .fold(EmailBuilder::new(), |builder, recipient| {
-            builder.to(recipient.clone())
+        .copied()
+        .filter(|r| !state.suppressed.contains(&r.addr.to_lowercase()))
+        .collect();
+    if recipients.is_empty() {
+        return Ok(Deliveries::new());
+    }
+    let dkim_key = match &config.dkim {
+        Some(dkim) => Some(dkim::Key::load(&dkim.private_key_file)?),
+        None => None,
+    };
+    let secret = match &config.unsubscribe {
+        Some(unsubscribe) => Some(unsubscribe::load_secret(unsubscribe)?),
+        None => None,
+    };
+    let (separate, shared): (Vec<&Recipient>, Vec<&Recipient>) =
+        recipients.into_iter().partition(|r| {
+            config.delivery == DeliveryMode::Individual || r.encryption.is_some()
+        });
+    let shared: Vec<&String> = shared.iter().map(|r| &r.addr).collect();
+    let mut messages: Vec<Message<'_>> = vec![];
+    if !shared.is_empty() {
+        // We need to fold over the vector of recipients, to update the builder
+        // value with each one.
+        let builder = match config.delivery {
+            DeliveryMode::Bcc => shared
+                .iter()
+                .fold(builder.clone(), |builder, r| builder.bcc((*r).clone()))
+                .to(config.sender_email_addr.clone()),
+            _ => shared
+                .iter()
+                .fold(builder.clone(), |builder, r| builder.to((*r).clone()))
+                .bcc(config.sender_email_addr.clone()),
+        };
+        messages.push(Message {
+            recipients: shared,
+            builder,
+            encryption: None,
+            separate: false,
+        });
+    }
+    messages.extend(separate.into_iter().map(|r| {
+        let builder = builder.clone().to(r.addr.clone());
+        let builder = match (&config.unsubscribe, &secret) {
+            (Some(unsubscribe), Some(secret)) => builder
+                .header((
+                    "List-Unsubscribe",
+                    format!("<{}>", unsubscribe::link(unsubscribe, secret, &r.addr)),
+                ))
+                .header(("List-Unsubscribe-Post", "List-Unsubscribe=One-Click")),
+            _ => builder,
+        };
+        Message {
+            recipients: vec![&r.addr],
+            builder,
+            encryption: r.encryption.as_ref(),
+            separate: true,
+        }
+    }));
+
+    // Send each message (threaded onto the first one its recipients got, if
+    // any), noting the outcome for each of its recipients.
+    let mut deliveries = Deliveries::new();
+    let mut any_sent = false;
+    let mut first_error = None;
+    for mut message in messages {
+        let thread = message.thread_key();
+        let message_id =
+            thread_message_id(config, state.thread.num_sent, thread.as_deref());
+        let root = match &thread {
+            Some(addr) => state.thread.root_message_ids.get(addr),
+            None => state.thread.root_message_id.as_ref(),
+        };
+        if let (true, Some(root)) = (config.threading, root) {
+            message.builder = message
+                .builder
+                .in_reply_to(root.clone())
+                .references(root.clone());
+        }
+        let delivery =
+            match dispatch(config, &message, message_id.as_deref(), dkim_key.as_ref()) {
+                Ok(()) => {
+                    any_sent = true;
+                    if let Some(id) = message_id {
+                        match thread {
+                            Some(addr) => {
+                                state.thread.root_message_ids.entry(addr).or_insert(id);
+                            }
+                            None => {
+                                state.thread.root_message_id.get_or_insert(id);
+                            }
+                        }
+                    }
+                    Delivery::Sent
+                }
+                Err(err) => {
+                    let delivery = Delivery::Failed(err.to_string());
+                    first_error.get_or_insert(err);
+                    delivery
+                }
+            };
+        for recipient in message.recipients {
+            deliveries.insert(recipient.clone(), delivery.clone());
+        }
+    }
+
+    // Give up if nothing at all was sent; otherwise record the successful send
+    // for next time.
+    if !deliveries.values().any(|d| *d == Delivery::Sent) {
+        return Err(first_error.unwrap_or_else(|| "no recipients".into()));
+    }
+    if any_sent && config.threading {
+        state.thread.num_sent += 1;
+    }
+    Ok(deliveries)
+}
+
+/// One email to be sent, and who it's for (and whether it's their own copy).
+struct Message<'a> {
+    recipients: Vec<&'a String>,
+    builder: EmailBuilder,
+    encryption: Option<&'a Encryption>,
+    separate: bool,
+}
+
+impl Message<'_> {
+    /// The recipient's (lowercased) address, if it's their own copy, which is
+    /// threaded separately.
+    fn thread_key(&self) -> Option<String> {
+        match (self.separate, self.recipients.as_slice()) {
+            (true, [addr]) => Some(addr.to_lowercase()),
+            _ => None,
+        }
+    }
+}
+
+/// Build and send one message, applying our own Message-ID if provided,
+/// encryption if required, and a DKIM signature if configured.
+fn dispatch(
+    config: &Config,
+    message: &Message<'_>,
+    message_id: Option<&str>,
+    dkim_key: Option<&dkim::Key>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let email: SendableEmail = message.builder.clone().build()?.into();
+    let envelope = email.envelope().clone();
+    let message_id =
+        message_id.map_or_else(|| email.message_id().to_string(), String::from);
+    let encrypted = message.encryption;
+    let mut message = email.message_to_string()?;
+    if config.threading {
+        message = replace_message_id(&message, &message_id);
+    }
+    if let Some(encryption) = encrypted {
+        message = encrypt::encrypt(config, encryption, &message)?;
+    }
+    if let (Some(dkim), Some(key)) = (&config.dkim, dkim_key) {
+        message = dkim::sign(dkim, key, &message, Utc::now())?;
+    }
+    let email = SendableEmail::new(envelope, message_id, message.into_bytes());
+    SendmailTransport::new_with_command(&config.sendmail_path).send(email)?;
+    Ok(())
+}
+
+/// The deterministic Message-ID for the `num`th email sent for this album (or
+/// `None` if threading is disabled). When a recipient gets their own copy,
+/// it's distinguished by a hash of their (lowercased) `addr`, which stays the
+/// same whoever else is on the list. The domain part is taken from the sender
+/// address, as recommended by RFC 5322.
+fn thread_message_id(config: &Config, num: u64, addr: Option<&str>) -> Option<String> {
+    if !config.threading {
+        return None;
+    }
+    let domain = sender_domain(config);
+    let part = addr
+        .map(|addr| format!(".{}", &hex::encode(Sha256::digest(addr.as_bytes()))[..16]))
+        .unwrap_or_default();
+    Some(format!(
+        "<icloud-biff.{}.{}{}@{}>",
+        config.album_id, num, part, domain
+    ))
+}
+
+/// The `List-Id` header (RFC 2919): the album name (as a quoted string, per
+/// RFC 5322), and an ID unique to the album and sender.
+fn list_id(config: &Config) -> String {
+    let name = config.album_name.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(
+        "\"{}\" <{}.icloud-biff.{}>",
+        name,
+        config.album_id,
+        sender_domain(config)
+    )
+}
+
+/// The domain part of the sender's email address.
+fn sender_domain(config: &Config) -> &str {
+    config
+        .sender_email_addr
+        .rsplit('@')
+        .next()
+        .unwrap_or("localhost")
+}
+
+/// Swap out the random Message-ID that `EmailBuilder::build()` always adds for
+/// the one provided.
+fn replace_message_id(message: &str, message_id: &str) -> String {
+    let (headers, body) =
+        message.split_at(message.find("\r\n\r\n").unwrap_or(message.len()));
+    let headers: Vec<String> = headers
+        .split("\r\n")
+        .map(|line| {
+            if line.to_ascii_lowercase().starts_with("message-id:") {
+                format!("Message-ID: {}", message_id)
+            } else {
+                line.to_string()
+            }
         })
-        // ...then add the other scalar fields normally...
-        .from((config.sender_email_addr.clone(), config.sender_email_name.clone()))
-        .bcc(config.sender_email_addr.clone())
-        .subject(subject)
-        .alternative_body(html, plaintext)
-        // ...aaand build.
-        .build()?;
-
-    // Send it
-    SendmailTransport::new_with_command(&config.sendmail_path)
-        .send(email.into())
-        .map_err(|e| e.into())
+        .collect();
+    format!("{}{}", headers.join("\r\n"), body)
+}
+
+/// Base64-encode some data, in lines of the maximum length allowed for MIME.
+pub fn base64_lines(data: &[u8]) -> String {
+    let encoded = BASE64.encode(data);
+    let lines: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(76)
+        .map(|line| std::str::from_utf8(line).expect("base64 is ASCII"))
+        .collect();
+    lines.join("\r\n")
+}
+
+/// Split a raw (CRLF-separated) message into its headers, as name/value pairs
+/// (where the value retains any folding), and body.
+pub fn split_message(message: &str) -> (Vec<(&str, &str)>, &str) {
+    let (header_block, body) = match message.find("\r\n\r\n") {
+        Some(n) => (&message[..n + 2], &message[n + 4..]),
+        None => (message, ""),
+    };
+
+    // Each header starts at a line that doesn't begin with whitespace, and
+    // continues over any folded lines that do.
+    let mut headers = vec![];
+    let mut start = 0;
+    let mut lines = header_block.split_inclusive("\r\n").peekable();
+    let mut offset = 0;
+    while let Some(line) = lines.next() {
+        offset += line.len();
+        let continued = lines
+            .peek()
+            .is_some_and(|next| next.starts_with(' ') || next.starts_with('\t'));
+        if !continued {
+            let header = header_block[start..offset].trim_end_matches("\r\n");
+            if let Some((name, value)) = header.split_once(':') {
+                headers.push((name, value));
+            }
+            start = offset;
+        }
+    }
+    (headers, body)
 }
 
 /// Encode an email as alternaive text/plain and text/html, but with a
 /// content-transfer-encoding of quoted-printable for the html, due to RFC
 /// 5322's maximum line limit of 998 characters excluding CRLF (and
-/// recommendation of 78 characters).
+/// recommendation of 78 characters). The text is quoted-printable too, since
+/// captions can contain any old Unicode.
+///
+/// If there's a contact sheet, it goes alongside them in a multipart/related
+/// (which lettre doesn't do, hence putting it together by hand).
 ///
 /// This is copied and modified from lettre_email::alternative().
 trait AddAlt {
-    fn alternative_body(self, body_html: String, body_text: String) -> EmailBuilder;
+    fn alternative_body(
+        self,
+        body_html: String,
+        body_text: String,
+        contact_sheet: Option<&ContactSheet>,
+    ) -> EmailBuilder;
 }
 
 impl AddAlt for EmailBuilder {
-    fn alternative_body(self, body_html: String, body_text: String) -> EmailBuilder {
+    fn alternative_body(
+        self,
+        body_html: String,
+        body_text: String,
+        contact_sheet: Option<&ContactSheet>,
+    ) -> EmailBuilder {
         let text = PartBuilder::new()
-            .body(body_text)
+            .body(quoted_printable::encode_to_str(
+                body_text.replace('\n', "\r\n"),
+            ))
             .header(("Content-Type", mime::TEXT_PLAIN_UTF_8.to_string()))
+            .header(("Content-Transfer-Encoding", "quoted-printable"))
             .build();
 
         let html = PartBuilder::new()
@@ -64,9 +376,53 @@ impl AddAlt for EmailBuilder {
         let alternate = PartBuilder::new()
             .message_type(MimeMultipartType::Alternative)
             .child(text)
-            .child(html);
+            .child(html)
+            .build();
+
+        let content = match contact_sheet {
+            Some(contact_sheet) => {
+                let image = PartBuilder::new()
+                    .body(base64_lines(&contact_sheet.jpeg))
+                    .header(("Content-Type", "image/jpeg; name=\"contact-sheet.jpg\""))
+                    .header(("Content-Transfer-Encoding", "base64"))
+                    .header((
+                        "Content-Disposition",
+                        "inline; filename=\"contact-sheet.jpg\"",
+                    ))
+                    .header(("Content-ID", format!("<{}>", sheet::CONTENT_ID)))
+                    .build();
+                let mut related = MimeMessage::new_blank_message();
+                related.headers.insert(Header::new(
+                    "Content-Type".to_string(),
+                    format!("multipart/related; boundary=\"{}\"", related.boundary),
+                ));
+                related.children = vec![alternate, image];
+                related
+            }
+            None => alternate,
+        };
+
+        self.message_type(MimeMultipartType::Mixed).child(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
 
-        self.message_type(MimeMultipartType::Mixed)
-            .child(alternate.build())
+    #[test]
+    fn list_ids() {
+        let config = testing::config(json!({}));
+        assert_eq!(
+            list_id(&config),
+            "\"Dogs\" <B0zAxqIORGhwx3u.icloud-biff.example.com>"
+        );
+        let config = testing::config(json!({"album-name": r#"The "Good" Dogs \o/"#}));
+        assert_eq!(
+            list_id(&config),
+            r#""The \"Good\" Dogs \\o/" <B0zAxqIORGhwx3u.icloud-biff.example.com>"#
+        );
     }
 }
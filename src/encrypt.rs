@@ -0,0 +1,267 @@
+//! Encrypt emails for individual recipients: PGP/MIME (RFC 3156) via `gpg`, or
+//! S/MIME (RFC 8551) via `openssl`
+//!
+//! Either way, the message's own content (the multipart/alternative body and
+//! its `Content-*` headers) becomes the encrypted entity, and the routing
+//! headers (From, To, Subject etc.) stay in the clear around it.
+
+use crate::email;
+use crate::types::*;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Encrypt a raw (CRLF-separated) message to a recipient's key.
+pub fn encrypt(
+    config: &Config,
+    encryption: &Encryption,
+    message: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Separate the content headers, which belong with the encrypted entity.
+    let (headers, body) = email::split_message(message);
+    let (content, outer): (Vec<_>, Vec<_>) = headers
+        .into_iter()
+        .partition(|(name, _)| name.to_ascii_lowercase().starts_with("content-"));
+    let entity = format!("{}\r\n{}", join_headers(&content), body);
+    let outer = join_headers(&outer);
+
+    match encryption {
+        Encryption::Pgp(key_file) => {
+            let armored = gpg(config, key_file, entity.as_bytes())?;
+            let armored = String::from_utf8(armored)?.replace("\r\n", "\n");
+            let armored = armored.trim_end().replace('\n', "\r\n");
+            let boundary = boundary(&armored);
+            Ok(format!(
+                "{outer}Content-Type: multipart/encrypted;\r\n\
+                 \tprotocol=\"application/pgp-encrypted\"; boundary=\"{b}\"\r\n\
+                 \r\n\
+                 This is an OpenPGP/MIME encrypted message (RFC 3156).\r\n\
+                 --{b}\r\n\
+                 Content-Type: application/pgp-encrypted\r\n\
+                 Content-Description: PGP/MIME version identification\r\n\
+                 \r\n\
+                 Version: 1\r\n\
+                 \r\n\
+                 --{b}\r\n\
+                 Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n\
+                 Content-Description: OpenPGP encrypted message\r\n\
+                 Content-Disposition: inline; filename=\"encrypted.asc\"\r\n\
+                 \r\n\
+                 {armored}\r\n\
+                 --{b}--\r\n",
+                outer = outer,
+                b = boundary,
+                armored = armored,
+            ))
+        }
+        Encryption::Smime(cert_file) => {
+            let der = run(
+                Command::new(&config.openssl_path)
+                    .args(["smime", "-encrypt", "-binary", "-aes256", "-outform", "DER"])
+                    .arg(cert_file),
+                entity.as_bytes(),
+            )?;
+            Ok(format!(
+                "{}Content-Type: application/pkcs7-mime; smime-type=enveloped-data;\r\n\
+                 \tname=\"smime.p7m\"\r\n\
+                 Content-Transfer-Encoding: base64\r\n\
+                 Content-Disposition: attachment; filename=\"smime.p7m\"\r\n\
+                 \r\n\
+                 {}\r\n",
+                outer,
+                email::base64_lines(&der),
+            ))
+        }
+    }
+}
+
+/// Encrypt some data to the public key in a file, returning it ASCII-armored.
+/// A throwaway home directory (private to us, and removed afterwards) keeps
+/// this independent of any user keyring.
+fn gpg(
+    config: &Config,
+    key_file: &str,
+    data: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let home = tempfile::Builder::new()
+        .prefix("icloud-biff-gpg.")
+        .tempdir()?;
+    run(
+        Command::new(&config.gpg_path)
+            .arg("--homedir")
+            .arg(home.path())
+            .args(["--batch", "--quiet", "--no-tty", "--armor"])
+            .args(["--trust-model", "always", "--recipient-file", key_file])
+            .arg("--encrypt"),
+        data,
+    )
+}
+
+/// Run a command with the provided input, returning its output if it
+/// succeeded.
+fn run(
+    command: &mut Command,
+    input: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Write the input from another thread, so a full output pipe can't
+    // deadlock us.
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    let written = writer.join().expect("writer thread panicked");
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "{:?} failed ({}): {}",
+            command,
+            output.status,
+            stderr.trim()
+        )
+        .into());
+    }
+    written?;
+    Ok(output.stdout)
+}
+
+/// Put headers (as returned by `email::split_message`) back together, each
+/// followed by CRLF.
+fn join_headers(headers: &[(&str, &str)]) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\r\n", name, value))
+        .collect()
+}
+
+/// A MIME boundary that can't appear in the (armored) content.
+fn boundary(content: &str) -> String {
+    let hash = Sha256::digest(content.as_bytes());
+    format!("=_icloud-biff_{}", hex::encode(&hash[..16]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use serde_json::json;
+    use std::path::Path;
+
+    static MESSAGE: &str = "From: bot@example.com\r\n\
+        To: mum@example.com\r\n\
+        Subject: New Dogs photos\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: 7bit\r\n\
+        \r\n\
+        Woof.\r\n";
+
+    /// What should be encrypted: the content headers and the body.
+    static ENTITY: &str = "Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: 7bit\r\n\
+        \r\n\
+        Woof.\r\n";
+
+    /// The headers left in the clear.
+    static OUTER: &str = "From: bot@example.com\r\n\
+        To: mum@example.com\r\n\
+        Subject: New Dogs photos\r\n\
+        MIME-Version: 1.0\r\n";
+
+    /// Whether a program can be run (so the test needing it is worth running).
+    fn available(program: &str) -> bool {
+        let found = Command::new(program).arg("--version").output().is_ok();
+        if !found {
+            eprintln!("{} not found, so skipping", program);
+        }
+        found
+    }
+
+    /// Run a program in a directory, with some input, returning its output.
+    fn output(program: &str, dir: &Path, args: &[&str], input: &[u8]) -> Vec<u8> {
+        run(Command::new(program).current_dir(dir).args(args), input).unwrap()
+    }
+
+    #[test]
+    fn pgp_round_trip() {
+        if !available("gpg") {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().to_str().unwrap();
+        let gpg = |args: &[&str], input: &[u8]| {
+            let mut all = vec!["--homedir", home, "--batch", "--quiet"];
+            all.extend(args);
+            output("gpg", dir.path(), &all, input)
+        };
+        gpg(
+            &["--passphrase", "", "--quick-gen-key", "mum@example.com"],
+            b"",
+        );
+        let key = gpg(&["--armor", "--export", "mum@example.com"], b"");
+        let key_file = dir.path().join("mum.asc");
+        std::fs::write(&key_file, key).unwrap();
+
+        let config = testing::config(json!({}));
+        let key_file = key_file.to_str().unwrap().to_string();
+        let encrypted = encrypt(&config, &Encryption::Pgp(key_file), MESSAGE).unwrap();
+        assert!(encrypted
+            .starts_with(&format!("{}Content-Type: multipart/encrypted;", OUTER)));
+        let start = encrypted.find("-----BEGIN PGP MESSAGE-----").unwrap();
+        let end = encrypted.find("-----END PGP MESSAGE-----").unwrap();
+        let armored = &encrypted[start..end + "-----END PGP MESSAGE-----".len()];
+        let decrypted = gpg(&["--decrypt"], armored.as_bytes());
+        assert_eq!(String::from_utf8(decrypted).unwrap(), ENTITY);
+    }
+
+    #[test]
+    fn smime_round_trip() {
+        if !available("openssl") {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let openssl =
+            |args: &[&str], input: &[u8]| output("openssl", dir.path(), args, input);
+        openssl(
+            &[
+                "req",
+                "-x509",
+                "-newkey",
+                "rsa:2048",
+                "-nodes",
+                "-keyout",
+                "key.pem",
+                "-out",
+                "cert.pem",
+                "-subj",
+                "/CN=mum@example.com",
+                "-days",
+                "1",
+            ],
+            b"",
+        );
+
+        let config = testing::config(json!({}));
+        let cert_file = dir.path().join("cert.pem").to_str().unwrap().to_string();
+        let encrypted = encrypt(&config, &Encryption::Smime(cert_file), MESSAGE).unwrap();
+        assert!(encrypted
+            .starts_with(&format!("{}Content-Type: application/pkcs7-mime;", OUTER)));
+        let (_, body) = email::split_message(&encrypted);
+        let der = BASE64.decode(body.replace("\r\n", "")).unwrap();
+        let decrypted = openssl(
+            &[
+                "smime", "-decrypt", "-binary", "-inform", "DER", "-inkey", "key.pem",
+            ],
+            &der,
+        );
+        assert_eq!(String::from_utf8(decrypted).unwrap(), ENTITY);
+    }
+}
@@ -0,0 +1,297 @@
+//! Exec notifier: run a command (eg a script) about new assets
+//!
+//! The command gets the details as JSON on its stdin, in the same format as
+//! the webhook payload (narrowed down to one asset, if it's run per asset).
+//! The most useful of them also go in its environment, for simple scripts.
+//! Its stdout and stderr both go to our stderr, so end up in our logs (but
+//! stay out of any report on stdout).
+
+use crate::fetch;
+use crate::notify::{Notifier, Update};
+use crate::report;
+use crate::state::State;
+use crate::types::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to check whether a command has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A configured command.
+#[derive(Debug)]
+pub struct Exec<'a> {
+    config: &'a Config,
+    exec: &'a ExecConfig,
+}
+
+impl<'a> Exec<'a> {
+    /// Set up a command.
+    pub fn new(config: &'a Config, exec: &'a ExecConfig) -> Self {
+        Exec { config, exec }
+    }
+
+    /// The key for this command's retries in the state.
+    fn key(&self) -> String {
+        self.exec.command.join(" ")
+    }
+
+    /// The thumbnail URLs for an update, plus any (fetched afresh) for the
+    /// assets of the runs being retried, as the ones from last time may well
+    /// have expired. (The runs can go ahead without them if need be.)
+    fn thumbnail_urls(
+        &self,
+        update: &Update<'_>,
+        retries: &[(Vec<&Asset>, Vec<&Guid>)],
+    ) -> HashMap<Checksum, Url> {
+        let mut thumbnail_urls = update.thumbnail_urls.clone();
+        let mut guids: Vec<&Guid> = vec![];
+        for asset in retries.iter().flat_map(|(assets, _)| assets) {
+            if !thumbnail_urls.contains_key(&asset.checksum)
+                && !guids.contains(&&asset.guid)
+            {
+                guids.push(&asset.guid);
+            }
+        }
+        if !guids.is_empty() {
+            match fetch::thumbnail_urls(&guids, self.config) {
+                Ok(urls) => thumbnail_urls.extend(urls),
+                Err(err) => {
+                    report::warn(format_args!("unable to fetch thumbnail URLs: {}", err))
+                }
+            }
+        }
+        thumbnail_urls
+    }
+
+    /// Run the command once, with some details, killing it if it takes too
+    /// long.
+    fn run(&self, details: &serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+        let (program, args) = self
+            .exec
+            .command
+            .split_first()
+            .ok_or("no command configured")?;
+        let mut child = Command::new(program)
+            .args(args)
+            .envs(environment(details))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::from(std::io::stderr()))
+            .spawn()?;
+
+        // Write the details from another thread, in case the command doesn't
+        // read them all (in which case the error is ignored).
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let input = serde_json::to_vec(details)?;
+        let writer = thread::spawn(move || stdin.write_all(&input));
+
+        let timeout = Duration::from_secs(self.exec.timeout_seconds);
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(format!("timed out after {}s", timeout.as_secs()).into());
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        let _ = writer.join();
+        if !status.success() {
+            return Err(status.to_string().into());
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for Exec<'_> {
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Anything that failed last time goes first (unless it's since
+        // disappeared from the album), then the new assets (there's nothing to
+        // do about removed ones).
+        let album: HashMap<&Guid, &Asset> = update
+            .album_assets
+            .iter()
+            .map(|asset| (&asset.guid, asset))
+            .collect();
+        let mut runs: Vec<(Vec<&Asset>, Vec<&Guid>)> = state
+            .exec_pending
+            .remove(&self.key())
+            .unwrap_or_default()
+            .iter()
+            .map(|guids| {
+                let assets: Vec<&Asset> = guids
+                    .iter()
+                    .filter_map(|guid| album.get(guid).copied())
+                    .collect();
+                (assets, vec![])
+            })
+            .filter(|(assets, _)| !assets.is_empty())
+            .collect();
+        let thumbnail_urls = self.thumbnail_urls(update, &runs);
+        if !update.new_assets.is_empty() {
+            match self.exec.per {
+                ExecPer::Run => {
+                    runs.push((update.assets(), update.removed_assets.clone()))
+                }
+                ExecPer::Asset => runs.extend(
+                    update
+                        .assets()
+                        .into_iter()
+                        .map(|asset| (vec![asset], vec![])),
+                ),
+            }
+        }
+
+        let mut failed = vec![];
+        let mut errors = vec![];
+        for (assets, removed_assets) in runs {
+            let details = Update::new(
+                self.config,
+                update.album_assets,
+                &assets,
+                &thumbnail_urls,
+                removed_assets,
+            );
+            if let Err(err) = self.run(&serde_json::to_value(&details)?) {
+                if self.exec.on_failure == OnFailure::Abort {
+                    return Err(err);
+                }
+                errors.push(err.to_string());
+                failed.push(assets.iter().map(|asset| asset.guid.clone()).collect());
+            }
+        }
+        if self.exec.on_failure == OnFailure::Retry && !failed.is_empty() {
+            state.exec_pending.insert(self.key(), failed);
+        }
+        match errors.as_slice() {
+            [] => Ok(()),
+            [error] => Err(error.clone().into()),
+            errors => {
+                Err(format!("{} runs failed: {}", errors.len(), errors.join("; ")).into())
+            }
+        }
+    }
+
+    fn has_retries(&self, state: &State) -> bool {
+        state.exec_pending.contains_key(&self.key())
+    }
+
+    fn aborts_on_failure(&self) -> bool {
+        self.exec.on_failure == OnFailure::Abort
+    }
+}
+
+impl fmt::Display for Exec<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "command {}", self.key())
+    }
+}
+
+/// The environment variables for a run: the album's details, how many new
+/// assets there are, and if there's just one, its details (with any that are
+/// missing, such as its caption, left unset).
+fn environment(details: &serde_json::Value) -> Vec<(String, String)> {
+    let new_assets = details["new-assets"].as_array().map(Vec::as_slice);
+    let mut env = vec![
+        ("ALBUM_NAME", &details["album"]["name"]),
+        ("ALBUM_URL", &details["album"]["url"]),
+    ];
+    if let Some([asset]) = new_assets {
+        for (name, field) in &[
+            ("GUID", "guid"),
+            ("TYPE", "type"),
+            ("CAPTION", "caption"),
+            ("CONTRIBUTOR", "contributor"),
+            ("THUMBNAIL_URL", "thumbnail-url"),
+            ("URL", "url"),
+        ] {
+            env.push((name, &asset[field]));
+        }
+    }
+    let mut env: Vec<(String, String)> = env
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((format!("ICLOUD_BIFF_{}", name), value.as_str()?.to_string()))
+        })
+        .collect();
+    env.push((
+        "ICLOUD_BIFF_NEW_COUNT".to_string(),
+        new_assets.map_or(0, <[_]>::len).to_string(),
+    ));
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    /// A failed run is retried with the asset's current details.
+    #[test]
+    fn retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = testing::config(json!({}));
+        // It records each run's new assets, and fails unless there's an "ok"
+        // file.
+        let exec: ExecConfig = serde_json::from_value(json!({
+            "command": [
+                "sh",
+                "-c",
+                "tr -d '\\n' >>\"$0/runs\"; echo >>\"$0/runs\"; test -e \"$0/ok\"",
+                dir.path(),
+            ],
+            "on-failure": "retry",
+        }))
+        .unwrap();
+        let exec = Exec::new(&config, &exec);
+        let mut state = State::default();
+        let assets = [testing::photo("G1"), testing::photo("G2")];
+
+        let no_urls = HashMap::new();
+        let update = Update::new(&config, &assets[..1], &[&assets[0]], &no_urls, vec![]);
+        assert!(exec.notify(&update, &mut state).is_err());
+        assert_eq!(
+            state.exec_pending[&exec.key()],
+            [[testing::id::<Guid>("G1")]]
+        );
+        assert!(exec.has_retries(&state));
+
+        std::fs::write(dir.path().join("ok"), "").unwrap();
+        let urls = testing::thumbnail_urls(&[&assets[0], &assets[1]]);
+        let update = Update::new(&config, &assets, &[&assets[1]], &urls, vec![]);
+        exec.notify(&update, &mut state).unwrap();
+        assert!(!exec.has_retries(&state));
+
+        let runs = std::fs::read_to_string(dir.path().join("runs")).unwrap();
+        let runs: Vec<serde_json::Value> = runs
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let new_assets: Vec<_> = runs
+            .iter()
+            .map(|run| {
+                let asset = &run["new-assets"][0];
+                (asset["guid"].clone(), asset["thumbnail-url"].clone())
+            })
+            .collect();
+        assert_eq!(
+            new_assets,
+            [
+                (json!("G1"), json!(null)),
+                (json!("G1"), json!("https://example.com/G1-thumb.jpg")),
+                (json!("G2"), json!("https://example.com/G2-thumb.jpg")),
+            ]
+        );
+    }
+}
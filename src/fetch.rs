@@ -1,5 +1,6 @@
 //! Fetch data from iCloud
 
+use crate::report;
 use crate::types::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -10,18 +11,20 @@ type AnyError = Box<dyn std::error::Error + Send + Sync + 'static>;
 ///////////////////////////////////////////////////////////////////////////////
 ///
 /// Synchronously fetch all the assets available from the iCloud photo album.
-pub fn all_assets(album_id: &AlbumId) -> Result<Vec<Asset>, AnyError> {
+pub fn all_assets(config: &Config) -> Result<Vec<Asset>, AnyError> {
     let post_data = serde_json::json!({ "streamCtag": null });
     // We use an async http client, but just block on it straight away...
-    let result = smol::block_on(
-        surf::post(album_id.all_assets())
-            .body_json(&post_data)?
-            .recv_json::<AllAssetResponse>(),
-    )?
+    let result = report::timed("webstream", || {
+        smol::block_on(
+            surf::post(config.album_id.all_assets())
+                .body_json(&post_data)?
+                .recv_json::<AllAssetResponse>(),
+        )
+    })?
     // ...and then process the result.
     .photos
     .into_iter()
-    .map(process)
+    .map(|photo| process(photo, config.thumbnail_size))
     .collect();
     Ok(result)
 }
@@ -48,6 +51,21 @@ struct RawAsset {
     // This is omitted for the default Photo type, and "video" for Video.
     #[serde(default)]
     media_asset_type: AssetType,
+
+    // Who posted it, and what they said about it (if anything).
+    #[serde(default)]
+    contributor_full_name: String,
+    #[serde(default)]
+    caption: Option<String>,
+
+    // The upload batch it was part of, when that was created (RFC 3339), and
+    // the comment posted with it (if any).
+    #[serde(default)]
+    batch_guid: Option<String>,
+    #[serde(default)]
+    batch_date_created: Option<String>,
+    #[serde(default)]
+    batch_comment: Option<String>,
 }
 
 /// Per-asset-at-a-particular-resolution data.
@@ -58,38 +76,95 @@ struct RawAssetSize {
     checksum: Checksum,
 }
 
-/// Parse the externally-defined data into our preferred internal format.
-fn process(photo: RawAsset) -> Asset {
-    // Choose the best thumbnail image from all the alternatives.
-    let thumbnail = if photo.media_asset_type == AssetType::Video {
-        // Video: there's a handy "PosterFrame" provided.
-        photo.derivatives.get("PosterFrame").unwrap()
-    } else {
-        // Photo: choose the smallest size available (specified as keys,
-        // that are stringified integers specifying max(width, height)).
-        let mut keys: Vec<_> = photo.derivatives.keys().collect();
-        keys.sort_by_cached_key(|s| s.parse::<i32>().expect("photo size"));
-        photo.derivatives.get(keys[0]).unwrap()
-    };
+/// Parse the externally-defined data into our preferred internal format, with
+/// thumbnails to be shown at `thumbnail_size` px on the long edge.
+fn process(photo: RawAsset, thumbnail_size: u16) -> Asset {
+    // The thumbnail images to choose from, with their dimensions.
+    let candidates: Vec<(&Checksum, u16, u16)> = photo
+        .derivatives
+        .iter()
+        .filter(|(key, _)| match photo.media_asset_type {
+            // Video: there's a handy "PosterFrame" provided (the others are
+            // videos).
+            AssetType::Video => *key == "PosterFrame",
+            // Photo: the keys are stringified integers specifying
+            // max(width, height).
+            AssetType::Photo => key.parse::<u16>().is_ok(),
+        })
+        .map(|(_, size)| {
+            let width = size.width.parse().expect("width");
+            let height = size.height.parse().expect("height");
+            (&size.checksum, width, height)
+        })
+        .collect();
+
+    // Choose the best ones for standard and double density screens, and fit
+    // the standard one into the display box (without enlarging it).
+    let (checksum, width, height) = best_fit(&candidates, thumbnail_size);
+    let (checksum_2x, _, _) = best_fit(&candidates, thumbnail_size.saturating_mul(2));
+    let scale = f32::from(thumbnail_size) / f32::from(width.max(height).max(1));
+    let fit = |x: u16| (f32::from(x) * scale.min(1.0)).round().max(1.0) as u16;
 
-    // Video thumbnails are disproportionately large vs photo ones.
-    let shrink = |x: u16| -> u16 {
-        match photo.media_asset_type {
-            AssetType::Photo => x / 2,
-            AssetType::Video => x / 6,
-        }
+    // The best version overall: the biggest image, or for a video, the biggest
+    // actual video (if there is one).
+    let pixels = |size: &RawAssetSize| {
+        let dimension = |x: &str| x.parse::<u64>().unwrap_or(0);
+        dimension(&size.width) * dimension(&size.height)
     };
+    let checksum_best = photo
+        .derivatives
+        .iter()
+        .filter(|(key, _)| match photo.media_asset_type {
+            AssetType::Video => *key != "PosterFrame",
+            AssetType::Photo => key.parse::<u16>().is_ok(),
+        })
+        .max_by_key(|(_, size)| pixels(size))
+        .map_or(checksum, |(_, size)| &size.checksum);
 
     // Create internal representation.
     Asset {
         guid: photo.photo_guid.clone(),
         asset_type: photo.media_asset_type,
-        checksum: thumbnail.checksum.clone(),
-        width: shrink(thumbnail.width.parse().expect("width")),
-        height: shrink(thumbnail.height.parse().expect("height")),
+        contributor: photo.contributor_full_name.clone(),
+        caption: non_empty(&photo.caption),
+        batch_guid: photo.batch_guid.clone(),
+        batch_comment: non_empty(&photo.batch_comment),
+        batch_date_created: photo
+            .batch_date_created
+            .as_ref()
+            .and_then(|date| date.parse().ok()),
+        checksum: checksum.clone(),
+        checksum_2x: Some(checksum_2x.clone()).filter(|c| c != checksum),
+        checksum_best: checksum_best.clone(),
+        width: fit(width),
+        height: fit(height),
     }
 }
 
+/// The smallest image at least `size` px on its long edge, or failing that,
+/// the largest one.
+fn best_fit<'a>(
+    candidates: &[(&'a Checksum, u16, u16)],
+    size: u16,
+) -> (&'a Checksum, u16, u16) {
+    let long_edge = |(_, width, height): &&(&Checksum, u16, u16)| *width.max(height);
+    candidates
+        .iter()
+        .filter(|candidate| long_edge(candidate) >= size)
+        .min_by_key(long_edge)
+        .or_else(|| candidates.iter().max_by_key(long_edge))
+        .copied()
+        .expect("asset has a thumbnail")
+}
+
+/// Trim some optional text, treating blank as absent.
+fn non_empty(text: &Option<String>) -> Option<String> {
+    text.as_deref()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(String::from)
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 ///
 /// Synchronously fetch the thumbnail data for the specified Guids.
@@ -98,11 +173,13 @@ pub fn thumbnail_urls(
     config: &Config,
 ) -> Result<HashMap<Checksum, Url>, AnyError> {
     // We use an async http client, but just block on it straight away...
-    let result = smol::block_on(
-        surf::post(config.album_id.asset_urls())
-            .body_json(&FetchThumbnailsRequest { photo_guids })?
-            .recv_json::<FetchThumbnailsResponse>(),
-    )?
+    let result = report::timed("webasseturls", || {
+        smol::block_on(
+            surf::post(config.album_id.asset_urls())
+                .body_json(&FetchThumbnailsRequest { photo_guids })?
+                .recv_json::<FetchThumbnailsResponse>(),
+        )
+    })?
     // ...and then process the result.
     .items
     .into_iter()
@@ -111,6 +188,62 @@ pub fn thumbnail_urls(
     Ok(result)
 }
 
+///////////////////////////////////////////////////////////////////////////////
+///
+/// Synchronously download some images, in order. Any that fail (including
+/// with an HTTP error status) are `None` (with a warning), rather than failing
+/// the lot.
+pub fn images(urls: &[&Url]) -> Vec<Option<Vec<u8>>> {
+    urls.iter()
+        .map(|url| {
+            download(url)
+                .map_err(|err| {
+                    report::warn(format_args!("unable to download {}: {}", url.0, err))
+                })
+                .ok()
+        })
+        .collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+///
+/// Synchronously download the best quality version of an asset (see
+/// `Asset::checksum_best`).
+pub fn best(asset: &Asset, config: &Config) -> Result<Vec<u8>, AnyError> {
+    let urls = thumbnail_urls(&[&asset.guid], config)?;
+    let url = urls
+        .get(&asset.checksum_best)
+        .ok_or_else(|| format!("no URL for {}", asset.checksum_best))?;
+    Ok(download(url)?)
+}
+
+/// The file extension and MIME type for a version of an asset, going by its
+/// `data`. (A video without an actual video version gets its poster frame.)
+pub fn file_type(data: &[u8], asset_type: AssetType) -> (&'static str, &'static str) {
+    match (image::guess_format(data), asset_type) {
+        (Ok(format), _) => (format.extensions_str()[0], format.to_mime_type()),
+        (Err(_), AssetType::Video) => ("mp4", "video/mp4"),
+        (Err(_), AssetType::Photo) => ("jpg", "image/jpeg"),
+    }
+}
+
+/// Synchronously download a URL, checking for an HTTP error status.
+fn download(url: &Url) -> Result<Vec<u8>, surf::Error> {
+    report::timed("download", || {
+        smol::block_on(async {
+            let mut response = surf::get(&url.0).await?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(surf::Error::from_str(
+                    status,
+                    format!("HTTP status {}", status),
+                ));
+            }
+            response.body_bytes().await
+        })
+    })
+}
+
 //
 // Types corresponding to the externally-defined JSON format
 //
@@ -0,0 +1,186 @@
+//! Render the whole album as a static HTML gallery
+//!
+//! The gallery is a directory of plain HTML pages: numbered index pages of
+//! thumbnails (newest first, in sections by day), and a page for each asset.
+//! The thumbnails are downloaded alongside them, since iCloud's URLs for them
+//! expire after a while. Running this again brings the gallery up to date,
+//! only downloading thumbnails that it doesn't have yet, and removing pages
+//! and thumbnails of assets that have since disappeared.
+
+use crate::fetch;
+use crate::html::{self, Nav};
+use crate::report;
+use crate::schedule;
+use crate::types::*;
+use crate::utils;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Convenience type for errors.
+type AnyError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The subdirectory the thumbnails are downloaded to.
+static THUMBS_DIR: &str = "thumbs";
+
+/// How many assets to ask iCloud for the thumbnail URLs of at once.
+const URL_BATCH_SIZE: usize = 25;
+
+/// Write the gallery to `dir` (creating it if need be), with `per_page`
+/// thumbnails on each index page.
+pub fn generate(config: &Config, dir: &str, per_page: usize) -> Result<(), AnyError> {
+    let dir = Path::new(dir);
+    let thumbs_dir = dir.join(THUMBS_DIR);
+    fs::create_dir_all(&thumbs_dir)?;
+
+    // Newest first (keeping the album's order within each upload, and putting
+    // anything undated at the end).
+    let all_assets = fetch::all_assets(config)?;
+    let mut assets: Vec<&Asset> = all_assets.iter().collect();
+    assets.sort_by_key(|asset| Reverse(asset.batch_date_created));
+
+    download_thumbnails(config, &thumbs_dir, &assets)?;
+
+    // Link to whichever thumbnails are there, leaving out any assets without
+    // one.
+    let mut thumbnail_urls: HashMap<Checksum, Url> = HashMap::new();
+    for checksum in assets.iter().flat_map(|a| checksums(a)) {
+        if thumbs_dir.join(thumbnail_file(checksum)).exists() {
+            let url = format!("{}/{}", THUMBS_DIR, thumbnail_file(checksum));
+            thumbnail_urls.insert(checksum.clone(), Url(url));
+        }
+    }
+    assets.retain(|asset| {
+        let present = thumbnail_urls.contains_key(&asset.checksum);
+        if !present {
+            report::warn(format_args!(
+                "leaving {} out of the gallery (no thumbnail)",
+                asset.guid
+            ));
+        }
+        present
+    });
+
+    // The index pages...
+    let time_zone = schedule::default_time_zone(config);
+    let per_page = per_page.max(1);
+    let pages: Vec<&[&Asset]> = assets.chunks(per_page).collect();
+    let num_pages = pages.len().max(1);
+    let mut written: HashSet<String> = HashSet::new();
+    for page in 0..num_pages {
+        let nav = Nav {
+            position: format!("Page {} of {}", page + 1, num_pages),
+            up: None,
+            newer: page.checked_sub(1).map(page_file),
+            older: Some(page + 1).filter(|&n| n < num_pages).map(page_file),
+        };
+        let html = html::gallery_page(
+            config,
+            pages.get(page).copied().unwrap_or_default(),
+            &thumbnail_urls,
+            time_zone,
+            &asset_file,
+            &nav,
+        );
+        written.insert(write(dir, page_file(page), &html)?);
+    }
+
+    // ...and the asset pages.
+    for (n, asset) in assets.iter().enumerate() {
+        let nav = Nav {
+            position: format!("{} of {}", n + 1, assets.len()),
+            up: Some(page_file(n / per_page)),
+            newer: n.checked_sub(1).map(|n| asset_file(assets[n])),
+            older: assets.get(n + 1).map(|asset| asset_file(asset)),
+        };
+        let html = html::gallery_asset(config, asset, &thumbnail_urls, time_zone, &nav);
+        written.insert(write(dir, asset_file(asset), &html)?);
+    }
+
+    // Tidy up anything left over from assets that have disappeared (or pages
+    // no longer needed).
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let ours = name.starts_with("page-") || name.starts_with("asset-");
+        if ours && name.ends_with(".html") && !written.contains(&name) {
+            fs::remove_file(dir.join(name))?;
+        }
+    }
+    let used: HashSet<String> = assets
+        .iter()
+        .flat_map(|a| checksums(a))
+        .map(thumbnail_file)
+        .collect();
+    for entry in fs::read_dir(&thumbs_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !used.contains(&name) {
+            fs::remove_file(thumbs_dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Download any of the assets' thumbnails that aren't already in `thumbs_dir`.
+/// Any that fail are warned about, and tried again next time.
+fn download_thumbnails(
+    config: &Config,
+    thumbs_dir: &Path,
+    assets: &[&Asset],
+) -> Result<(), AnyError> {
+    let missing = |asset: &&&Asset| {
+        checksums(asset)
+            .any(|checksum| !thumbs_dir.join(thumbnail_file(checksum)).exists())
+    };
+    let to_fetch: Vec<&Asset> = assets.iter().filter(missing).copied().collect();
+    for batch in to_fetch.chunks(URL_BATCH_SIZE) {
+        let guids: Vec<&Guid> = batch.iter().map(|a| &a.guid).collect();
+        let urls = fetch::thumbnail_urls(&guids, config)?;
+        let wanted: Vec<(&Checksum, &Url)> = batch
+            .iter()
+            .flat_map(|a| checksums(a))
+            .filter(|checksum| !thumbs_dir.join(thumbnail_file(checksum)).exists())
+            .filter_map(|checksum| Some((checksum, urls.get(checksum)?)))
+            .collect();
+        let images: Vec<&Url> = wanted.iter().map(|(_, url)| *url).collect();
+        for ((checksum, _), data) in wanted.iter().zip(fetch::images(&images)) {
+            if let Some(data) = data {
+                fs::write(thumbs_dir.join(thumbnail_file(checksum)), data)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The checksums of an asset's thumbnails (standard and any high-DPI one).
+fn checksums(asset: &Asset) -> impl Iterator<Item = &Checksum> {
+    std::iter::once(&asset.checksum).chain(&asset.checksum_2x)
+}
+
+/// The file name of an index page (counting from 0), the first being the
+/// gallery's front page.
+fn page_file(page: usize) -> String {
+    match page {
+        0 => "index.html".to_string(),
+        page => format!("page-{}.html", page + 1),
+    }
+}
+
+/// The file name of an asset's page.
+fn asset_file(asset: &Asset) -> String {
+    format!(
+        "asset-{}.html",
+        utils::safe_file_name(&asset.guid.to_string())
+    )
+}
+
+/// The file name of a thumbnail (within `THUMBS_DIR`).
+fn thumbnail_file(checksum: &Checksum) -> String {
+    format!("{}.jpg", utils::safe_file_name(&checksum.to_string()))
+}
+
+/// Write a page, returning its file name.
+fn write(dir: &Path, name: String, html: &str) -> Result<String, std::io::Error> {
+    fs::write(dir.join(&name), html)?;
+    Ok(name)
+}
@@ -1,9 +1,13 @@
-//! Compose the HTML message body
+//! Compose the HTML (and plain text) message body, and the gallery pages
 
+use crate::sheet::{self, ContactSheet};
 use crate::types::*;
+use crate::utils;
+use chrono::NaiveDate;
+use chrono_tz::Tz;
 use horrorshow::helper::doctype;
-use horrorshow::{html, Raw};
-use std::collections::HashMap;
+use horrorshow::{box_html, html, Raw, RenderBox};
+use std::collections::{HashMap, HashSet};
 
 /// # Render assets to HTML
 ///
@@ -13,225 +17,846 @@ use std::collections::HashMap;
 ///
 /// The images are linked directly from the iCloud website, to avoid bloating an
 /// HTML email, and avoid issues with multiple image embedding in some email
-/// clients. The one embedded image (a play button to disambiguate videos) is
-/// included as base64 CSS, to avoid any attachment requirements.
+/// clients.
+///
+/// Since many email clients (notably Gmail and Outlook) strip `<style>` blocks
+/// and ignore modern CSS, the layout is built from nested tables, with all
+/// styling inline on each element.
+///
+/// The new `assets` are grouped by upload batch, each with a heading saying who
+/// added what when. Only the `shown` ones get thumbnails: if that's fewer than
+/// all of them, then the rest are summarised with a link to the album. Dates
+/// are shown in the recipient's `time_zone`.
+///
+/// If there's a `contact_sheet` (attached to the email), it's shown instead of
+/// the individual thumbnails.
 pub fn build(
     config: &Config,
-    assets: Vec<&Asset>,
+    assets: &[&Asset],
+    shown: Vec<&Asset>,
     thumbnail_urls: HashMap<Checksum, Url>,
+    time_zone: Tz,
+    contact_sheet: Option<&ContactSheet>,
 ) -> String {
-    format!(
+    let css = css(&shown);
+    let content = fragment(
+        config,
+        assets,
+        shown,
+        &thumbnail_urls,
+        time_zone,
+        contact_sheet,
+    );
+    document(&format!("New {} photos", config.album_name), css, &content)
+}
+
+/// # Render the content of the HTML document
+///
+/// This is the body of the document `build` creates (taking the same
+/// parameters), as a fragment to embed elsewhere (eg in a feed). It's fully
+/// styled inline, so works without the `<style>` block (which just adds dark
+/// mode and a narrow-screen layout).
+pub fn fragment(
+    config: &Config,
+    assets: &[&Asset],
+    shown: Vec<&Asset>,
+    thumbnail_urls: &HashMap<Checksum, Url>,
+    time_zone: Tz,
+    contact_sheet: Option<&ContactSheet>,
+) -> String {
+    let num_new = assets.len();
+    let num_more = num_new - shown.len();
+    let upload_dates = upload_dates(assets, time_zone);
+    let groups = match contact_sheet {
+        Some(_) => vec![],
+        None => groups(assets, &shown, time_zone, true),
+    };
+    let asset_link = |asset: &Asset| config.album_id.asset_url(&asset.guid);
+    let groups: Vec<String> = groups
+        .iter()
+        .map(|group| group_rows(group, thumbnail_urls, &asset_link))
+        .collect();
+    // The size to show any contact sheet at (scaled down to fit, if need be).
+    let contact_sheet_size = contact_sheet.map(|sheet| {
+        let width = sheet.width.min(CONTACT_SHEET_WIDTH);
+        (width, sheet.height * width / sheet.width)
+    });
+    container(&format!(
         "{}",
         html! {
-            : doctype::HTML;
-            html {
-                head {
-                    title : format!("New {} photos", config.album_name);
-                    style : css();
-                }
-                body {
-                    p(class="emph") {
-                        : format!("There are {} new photos available in ", assets.len());
-                        a(href = &config.album_id.url()) {
+            tr {
+                td(align = "center") {
+                    p(class = "text", style = style::EMPH) {
+                        : format!("There are {} new photos available in ", num_new);
+                        a(href = &config.album_id.url(), class = "link", style = style::LINK) {
                             : format!("your {} shared photo album", config.album_name)
                         }
                         : "."
-                     }
-                     p { :"You may be able to see some small blurry versions below,
+                    }
+                    @ if let Some(upload_dates) = &upload_dates {
+                        p(class = "text", style = style::TEXT) : upload_dates;
+                    }
+                    p(class = "text", style = style::TEXT) {
+                        : "You may be able to see some small blurry versions below,
                            depending on your email app's security preferences. Whether
                            you can, or just see empty boxes, please click on the link above,
                            or one of the pictures below, to see the photos or videos at
                            full resolution."
                     }
-                    br;
-                    div(class = "container") {
-                        @ for asset in &assets {
-                            a(href = &config.album_id.asset_url(&asset.guid)) {
-                                img(width  = asset.width,
-                                    height = asset.height,
-                                    src    = &thumbnail_urls.get(&asset.checksum).unwrap().0);
-                                @ if asset.asset_type == AssetType::Video {
-                                    div(class = "play-button")
+                }
+            }
+            @ if let Some((width, height)) = contact_sheet_size {
+                tr {
+                    td(align = "center", style = style::THUMB) {
+                        a(href = &config.album_id.url()) {
+                            img(width  = width,
+                                height = height,
+                                alt    = "The new photos",
+                                src    = format!("cid:{}", sheet::CONTENT_ID),
+                                style  = style::CONTACT_SHEET);
+                        }
+                    }
+                }
+            }
+            @ for rows in &groups {
+                : Raw(rows);
+            }
+            @ if num_more > 0 {
+                tr {
+                    td(align = "center") {
+                        p(class = "text", style = style::EMPH) {
+                            : format!("...and {} more. ", num_more);
+                            a(href = &config.album_id.url(), class = "link", style = style::LINK) {
+                                : "See them all in the album."
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    ))
+}
+
+//////////////////////////////////////////////////////////////////////////////
+///
+/// # Where a gallery page sits among the others
+///
+/// A description of the page (eg "Page 2 of 5"), shown linking `up` to the
+/// index page it's on (if any), between links to the `newer` and `older`
+/// pages either side (if any).
+#[derive(Debug)]
+pub struct Nav {
+    pub position: String,
+    pub up: Option<String>,
+    pub newer: Option<String>,
+    pub older: Option<String>,
+}
+
+/// # Render a gallery index page
+///
+/// A complete HTML document for one of the static gallery's index pages (see
+/// `gallery`), showing its `assets` (newest first) in a section for each day
+/// they were added (in the `time_zone`), each grouped by upload batch as in
+/// the emails. The thumbnails link to `asset_link(asset)` rather than iCloud.
+pub fn gallery_page(
+    config: &Config,
+    assets: &[&Asset],
+    thumbnail_urls: &HashMap<Checksum, Url>,
+    time_zone: Tz,
+    asset_link: &dyn Fn(&Asset) -> String,
+    nav: &Nav,
+) -> String {
+    // Split the page into days (the assets being in date order).
+    let mut days: Vec<(Option<NaiveDate>, Vec<&Asset>)> = vec![];
+    for asset in assets {
+        let date = day(asset, time_zone);
+        match days.last_mut() {
+            Some((last, day_assets)) if *last == date => day_assets.push(asset),
+            _ => days.push((date, vec![asset])),
+        }
+    }
+    let days: Vec<(String, Vec<String>)> = days
+        .iter()
+        .map(|(date, day_assets)| {
+            let heading = match date {
+                Some(date) => date.format(LONG_DATE_FORMAT).to_string(),
+                None => "Undated".to_string(),
+            };
+            let groups = groups(day_assets, day_assets, time_zone, false)
+                .iter()
+                .map(|group| group_rows(group, thumbnail_urls, asset_link))
+                .collect();
+            (heading, groups)
+        })
+        .collect();
+    let nav_rows = nav_rows(nav);
+    let content = container(&format!(
+        "{}",
+        html! {
+            tr {
+                td(align = "center") {
+                    p(class = "text", style = style::EMPH) {
+                        a(href = &config.album_id.url(), class = "link", style = style::LINK) {
+                            : &config.album_name
+                        }
+                    }
+                }
+            }
+            : Raw(&nav_rows);
+            @ for (heading, groups) in &days {
+                tr {
+                    td(align = "center") {
+                        p(class = "text", style = style::DAY) : heading;
+                    }
+                }
+                @ for rows in groups {
+                    : Raw(rows);
+                }
+            }
+            : Raw(&nav_rows);
+        }
+    ));
+    let title = format!("{} ({})", config.album_name, nav.position);
+    document(&title, css(assets), &content)
+}
+
+/// # Render a gallery asset page
+///
+/// A complete HTML document for one asset in the static gallery (see
+/// `gallery`): its high-DPI thumbnail where there is one (linking to the full
+/// resolution version on iCloud), with its whole caption, and who added it
+/// when (in the `time_zone`).
+pub fn gallery_asset(
+    config: &Config,
+    asset: &Asset,
+    thumbnail_urls: &HashMap<Checksum, Url>,
+    time_zone: Tz,
+    nav: &Nav,
+) -> String {
+    let asset_url = config.album_id.asset_url(&asset.guid);
+    let thumbnail_url = thumbnail_urls.get(&asset.checksum).unwrap().0.clone();
+    // Photos are shown bigger if they can be.
+    let (image_url, scale) =
+        match asset.checksum_2x.as_ref().map(|c| thumbnail_urls.get(c)) {
+            Some(Some(url_2x)) => (url_2x.0.clone(), 2),
+            _ => (thumbnail_url.clone(), 1),
+        };
+    let added = match (asset.contributor.as_str(), day(asset, time_zone)) {
+        ("", None) => None,
+        ("", Some(date)) => Some(format!("Added on {}", date.format(LONG_DATE_FORMAT))),
+        (contributor, None) => Some(format!("Added by {}", contributor)),
+        (contributor, Some(date)) => Some(format!(
+            "Added by {} on {}",
+            contributor,
+            date.format(LONG_DATE_FORMAT)
+        )),
+    };
+    let nav_rows = nav_rows(nav);
+    let content = container(&format!(
+        "{}",
+        html! {
+            tr {
+                td(align = "center") {
+                    p(class = "text", style = style::EMPH) {
+                        a(href = &config.album_id.url(), class = "link", style = style::LINK) {
+                            : &config.album_name
+                        }
+                    }
+                }
+            }
+            : Raw(&nav_rows);
+            tr {
+                td(align = "center", style = style::THUMB) {
+                    @ if asset.asset_type == AssetType::Video {
+                        : video(asset, thumbnail_url.clone(), asset_url.clone());
+                    } else {
+                        a(href = &asset_url) {
+                            img(width  = u32::from(asset.width) * scale,
+                                height = u32::from(asset.height) * scale,
+                                alt    = alt_text(asset),
+                                src    = &image_url,
+                                style  = style::LARGE_IMG);
+                        }
+                    }
+                }
+            }
+            tr {
+                td(align = "center") {
+                    @ if let Some(caption) = &asset.caption {
+                        p(class = "text", style = style::TEXT) : caption;
+                    }
+                    @ if let Some(added) = &added {
+                        p(class = "text", style = style::TEXT) : added;
+                    }
+                    @ if let Some(comment) = &asset.batch_comment {
+                        p(class = "text", style = style::COMMENT) : format!("\u{201c}{}\u{201d}", comment);
+                    }
+                    p(class = "text", style = style::TEXT) {
+                        a(href = &asset_url, class = "link", style = style::LINK) {
+                            : "See it at full resolution on iCloud"
+                        }
+                    }
+                }
+            }
+            : Raw(&nav_rows);
+        }
+    ));
+    let title = format!("{}: {}", config.album_name, alt_text(asset));
+    document(&title, css(&[asset]), &content)
+}
+
+/// Render a gallery page's navigation links, as a table row.
+fn nav_rows(nav: &Nav) -> String {
+    format!(
+        "{}",
+        html! {
+            tr {
+                td(align = "center") {
+                    p(class = "text", style = style::TEXT) {
+                        @ if let Some(newer) = &nav.newer {
+                            a(href = newer, class = "link", style = style::LINK) : "\u{2039} Newer";
+                            : " \u{b7} ";
+                        }
+                        @ if let Some(up) = &nav.up {
+                            a(href = up, class = "link", style = style::LINK) : &nav.position;
+                        } else {
+                            : &nav.position;
+                        }
+                        @ if let Some(older) = &nav.older {
+                            : " \u{b7} ";
+                            a(href = older, class = "link", style = style::LINK) : "Older \u{203a}";
+                        }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Wrap some `content` up as a complete HTML document, with a `title` and
+/// embedded `css`.
+fn document(title: &str, css: Raw<String>, content: &str) -> String {
+    format!(
+        "{}",
+        html! {
+            : doctype::HTML;
+            html {
+                head {
+                    meta(http-equiv = "Content-Type", content = "text/html; charset=utf-8");
+                    meta(name = "viewport", content = "width=device-width, initial-scale=1");
+                    meta(name = "color-scheme", content = "light dark");
+                    meta(name = "supported-color-schemes", content = "light dark");
+                    title : title;
+                    style : &css;
+                }
+                body(class = "body", style = style::BODY) {
+                    : Raw(content);
+                }
+            }
+        }
+    )
+}
+
+/// Wrap some table `rows` in the outer tables that centre them, in a column
+/// of limited width.
+fn container(rows: &str) -> String {
+    format!(
+        "{}",
+        html! {
+            table(role = "presentation", width = "100%", border = "0",
+                  cellpadding = "0", cellspacing = "0", class = "body",
+                  style = style::BODY) {
+                tr {
+                    td(align = "center", style = style::OUTER) {
+                        table(role = "presentation", width = "100%", border = "0",
+                              cellpadding = "0", cellspacing = "0",
+                              style = style::CONTAINER) {
+                            : Raw(rows);
+                        }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Render a group as table rows: its heading (and any comment), then its
+/// thumbnails (and their captions) side by side, each linking to
+/// `asset_link(asset)`.
+fn group_rows(
+    group: &Group<'_>,
+    thumbnail_urls: &HashMap<Checksum, Url>,
+    asset_link: &dyn Fn(&Asset) -> String,
+) -> String {
+    let thumbnail =
+        |asset: &Asset| thumbnail_urls.get(&asset.checksum).unwrap().0.clone();
+    let srcset = |asset: &Asset| {
+        let url_2x = asset
+            .checksum_2x
+            .as_ref()
+            .and_then(|c| thumbnail_urls.get(c));
+        match url_2x {
+            Some(url_2x) => format!("{} 1x, {} 2x", thumbnail(asset), url_2x.0),
+            None => format!("{} 1x", thumbnail(asset)),
+        }
+    };
+    format!(
+        "{}",
+        html! {
+            tr {
+                td(align = "center") {
+                    p(class = "text", style = style::GROUP) : &group.heading;
+                    @ if let Some(comment) = group.comment {
+                        p(class = "text", style = style::COMMENT) : format!("\u{201c}{}\u{201d}", comment);
+                    }
+                }
+            }
+            tr {
+                td(align = "center") {
+                    : Raw(MSO_TABLE_START);
+                    @ for (n, asset) in group.shown.iter().enumerate() {
+                        @ if n > 0 && n % COLUMNS == 0 {
+                            : Raw(MSO_NEXT_ROW);
+                        }
+                        : Raw(MSO_CELL_START);
+                        div(class = "thumb",
+                            style = format!("{} width: {}px;", style::THUMB, asset.width + 2)) {
+                            @ if asset.asset_type == AssetType::Video {
+                                : video(asset, thumbnail(asset), asset_link(asset));
+                            } else {
+                                a(href = asset_link(asset)) {
+                                    img(width  = asset.width,
+                                        height = asset.height,
+                                        alt    = alt_text(asset),
+                                        src    = thumbnail(asset),
+                                        srcset = srcset(asset),
+                                        style  = style::IMG);
                                 }
                             }
+                            @ if let Some(caption) = caption(asset) {
+                                p(class = "text", style = style::CAPTION) : caption;
+                            }
                         }
+                        : Raw(MSO_CELL_END);
                     }
-                    br;
+                    : Raw(MSO_TABLE_END);
                 }
             }
         }
     )
 }
 
-/// Embedded CSS.
+/// Render a video thumbnail: the poster frame as the background of a cell,
+/// with a play button on top (linking to `asset_url`), which unlike an
+/// absolutely positioned overlay, survives email clients' sanitising. Outlook
+/// for Windows doesn't do CSS background images at all, so it gets the same
+/// via VML in a conditional comment.
+fn video(asset: &Asset, thumbnail_url: String, asset_url: String) -> Box<dyn RenderBox> {
+    let (width, height) = (asset.width, asset.height);
+    let alt_text = alt_text(asset);
+    let video_class = video_class(asset);
+    let vml_start = format!(
+        "<!--[if gte mso 9]><v:rect xmlns:v=\"urn:schemas-microsoft-com:vml\" \
+         fill=\"true\" stroke=\"false\" style=\"width:{}px;height:{}px;\">\
+         <v:fill type=\"frame\" src=\"{}\" color=\"#000000\" />\
+         <v:textbox inset=\"0,0,0,0\"><![endif]-->",
+        width,
+        height,
+        utils::escape_html(&thumbnail_url),
+    );
+    let vml_end = "<!--[if gte mso 9]></v:textbox></v:rect><![endif]-->";
+    box_html! {
+        table(role = "presentation", border = "0", cellpadding = "0", cellspacing = "0",
+              class = "video") {
+            tr {
+                td(width = width, height = height, align = "center", valign = "middle",
+                   background = &thumbnail_url,
+                   style = format!("{} width: {}px; height: {}px; background-image: url('{}');",
+                                   style::VIDEO, width, height, thumbnail_url)) {
+                    : Raw(vml_start);
+                    a(href = asset_url, title = alt_text, class = video_class,
+                      style = format!("{} width: {}px; height: {}px; line-height: {}px;",
+                                      style::VIDEO_LINK, width, height, height)) {
+                        span(style = style::PLAY) : "\u{25b6}\u{fe0e}";
+                    }
+                    : Raw(vml_end);
+                }
+            }
+        }
+    }
+}
+
+/// # Render assets to plain text
+///
+/// A link to the album, followed by the captions of any of the shown `assets`
+/// that have them (each with a link to the asset).
+pub fn plaintext(config: &Config, assets: &[&Asset]) -> String {
+    let mut text = format!(
+        "New {} photos are available at {}\n",
+        config.album_name,
+        config.album_id.url()
+    );
+    let captioned: Vec<String> = assets
+        .iter()
+        .filter_map(|asset| {
+            let caption = caption(asset)?;
+            Some(format!(
+                " - {} ({})\n",
+                caption,
+                config.album_id.asset_url(&asset.guid)
+            ))
+        })
+        .collect();
+    if !captioned.is_empty() {
+        text += "\nCaptions:\n";
+        text += &captioned.concat();
+    }
+    text
+}
+
+/// An asset's caption (if any), truncated to a sensible length for showing
+/// under a thumbnail.
+pub fn caption(asset: &Asset) -> Option<String> {
+    let caption = asset.caption.as_deref()?;
+    Some(match caption.char_indices().nth(MAX_CAPTION_CHARS) {
+        Some((end, _)) => format!("{}\u{2026}", caption[..end].trim_end()),
+        None => caption.to_string(),
+    })
+}
+
+/// Alternative text for an asset's thumbnail: its caption, or failing that,
+/// what sort of asset it is.
+pub fn alt_text(asset: &Asset) -> String {
+    caption(asset).unwrap_or_else(|| match asset.asset_type {
+        AssetType::Photo => "Photo".to_string(),
+        AssetType::Video => "Video".to_string(),
+    })
+}
+
+/// The assets uploaded together, with a heading describing them, and the
+/// comment posted with them (if any).
+struct Group<'a> {
+    heading: String,
+    comment: Option<&'a str>,
+    shown: Vec<&'a Asset>,
+}
+
+/// What assets are grouped by: their upload batch where known, otherwise the
+/// contributor and day.
+#[derive(PartialEq)]
+enum GroupKey<'a> {
+    Batch(&'a str),
+    Day(&'a str, Option<NaiveDate>),
+}
+
+/// Group the assets (in order of first appearance), leaving out any groups
+/// with nothing shown. The headings say what day each group was added, if
+/// `dated`.
+fn groups<'a>(
+    assets: &[&'a Asset],
+    shown: &[&'a Asset],
+    time_zone: Tz,
+    dated: bool,
+) -> Vec<Group<'a>> {
+    let date = |asset: &Asset| day(asset, time_zone);
+    let mut grouped: Vec<(GroupKey<'a>, Vec<&'a Asset>)> = vec![];
+    for asset in assets {
+        let key = match &asset.batch_guid {
+            Some(batch_guid) => GroupKey::Batch(batch_guid),
+            None => GroupKey::Day(&asset.contributor, date(asset)),
+        };
+        match grouped.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, group)) => group.push(asset),
+            None => grouped.push((key, vec![asset])),
+        }
+    }
+
+    let shown: HashSet<&Guid> = shown.iter().map(|asset| &asset.guid).collect();
+    grouped
+        .into_iter()
+        .map(|(_, assets)| Group {
+            heading: heading(
+                &assets,
+                assets
+                    .iter()
+                    .filter_map(|a| date(a))
+                    .min()
+                    .filter(|_| dated),
+            ),
+            comment: assets.iter().find_map(|a| a.batch_comment.as_deref()),
+            shown: assets
+                .into_iter()
+                .filter(|asset| shown.contains(&asset.guid))
+                .collect(),
+        })
+        .filter(|group| !group.shown.is_empty())
+        .collect()
+}
+
+/// Describe a group of assets, eg "Alice added 12 photos on Saturday 4 May".
+fn heading(assets: &[&Asset], date: Option<NaiveDate>) -> String {
+    let contributor = match assets[0].contributor.as_str() {
+        "" => "Someone",
+        contributor => contributor,
+    };
+    let date = date
+        .map(|date| format!(" on {}", date.format(DATE_FORMAT)))
+        .unwrap_or_default();
+    format!("{} added {}{}", contributor, counts(assets), date)
+}
+
+/// Count assets by type, eg "12 photos and 1 video".
+pub fn counts(assets: &[&Asset]) -> String {
+    let count = |asset_type, noun: &str| match assets
+        .iter()
+        .filter(|a| a.asset_type == asset_type)
+        .count()
+    {
+        0 => None,
+        1 => Some(format!("1 {}", noun)),
+        n => Some(format!("{} {}s", n, noun)),
+    };
+    let counts: Vec<String> = count(AssetType::Photo, "photo")
+        .into_iter()
+        .chain(count(AssetType::Video, "video"))
+        .collect();
+    counts.join(" and ")
+}
+
+/// Describe when the assets were uploaded (if known), eg "Added on Saturday 4
+/// May", or "Added between Saturday 4 May and Monday 6 May".
+fn upload_dates(assets: &[&Asset], time_zone: Tz) -> Option<String> {
+    let dates = assets.iter().filter_map(|a| day(a, time_zone));
+    let first = dates.clone().min()?;
+    let last = dates.max()?;
+    let format = |date: NaiveDate| date.format(DATE_FORMAT).to_string();
+    Some(if first == last {
+        format!("Added on {}", format(first))
+    } else {
+        format!("Added between {} and {}", format(first), format(last))
+    })
+}
+
+/// The day an asset was added (if known), in the `time_zone`.
+fn day(asset: &Asset, time_zone: Tz) -> Option<NaiveDate> {
+    asset
+        .batch_date_created
+        .map(|date| date.with_timezone(&time_zone).date_naive())
+}
+
+/// Captions longer than this are truncated.
+const MAX_CAPTION_CHARS: usize = 100;
+
+/// How dates are shown, eg "Saturday 4 May".
+static DATE_FORMAT: &str = "%A %-d %B";
+
+/// How dates are shown in the gallery (which spans years), eg "Saturday 4 May
+/// 2024".
+static LONG_DATE_FORMAT: &str = "%A %-d %B %Y";
+
+/// The most a contact sheet is shown across (it's scaled down to fit).
+const CONTACT_SHEET_WIDTH: u32 = 620;
+
+/// How many thumbnails to show side by side (at most, and in Outlook, which
+/// gets a fixed table rather than reflowing).
+const COLUMNS: usize = 3;
+
+// Outlook for Windows can't lay out inline blocks, so it gets a "ghost" table
+// instead, which everything else ignores.
+static MSO_TABLE_START: &str = "<!--[if mso]><table role=\"presentation\" border=\"0\" \
+                                cellpadding=\"0\" cellspacing=\"0\"><tr><![endif]-->";
+static MSO_NEXT_ROW: &str = "<!--[if mso]></tr><tr><![endif]-->";
+static MSO_CELL_START: &str = "<!--[if mso]><td valign=\"top\"><![endif]-->";
+static MSO_CELL_END: &str = "<!--[if mso]></td><![endif]-->";
+static MSO_TABLE_END: &str = "<!--[if mso]></tr></table><![endif]-->";
+
+/// The class identifying a video's link, for resizing it on narrow screens.
+fn video_class(asset: &Asset) -> String {
+    let id: String = asset
+        .guid
+        .to_string()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    format!("video-{}", id)
+}
+
+/// Embedded CSS, for the clients that support it: a dark colour scheme, and
+/// two columns of thumbnails on narrow screens.
+///
+/// Since the inline styles take precedence, everything here is `!important`.
+/// On narrow screens, images are scaled to fit, keeping their aspect ratio;
+/// each video's play button link is given padding (relative to the width, and
+/// allowing for the 48px button) to do the same for its background.
 ///
 /// Uses `Raw` to avoid HTML escaping of eg "quotes"
-fn css() -> Raw<String> {
+fn css(shown: &[&Asset]) -> Raw<String> {
+    let videos: String = shown
+        .iter()
+        .filter(|asset| asset.asset_type == AssetType::Video)
+        .map(|asset| {
+            let padding = 50.0 * f32::from(asset.height) / f32::from(asset.width.max(1));
+            format!(
+                ".{} {{ padding: calc({:.2}% - 24px) 0 !important; }}\n",
+                video_class(asset),
+                padding
+            )
+        })
+        .collect();
     let s = r#"
-        body {
-            font-family: -apple-system, BlinkMacSystemFont, Roboto, sans-serif;
-            text-align: center;
-        }
-        div.container {
-            display: flex;
-            flex-wrap: wrap;
-            justify-content: space-around;
-            align-items: center;
-            align-content: center;
-        }
-        .emph {
-            font-weight: bold;
-            font-size: 120%;
+        :root {
+            color-scheme: light dark;
+            supported-color-schemes: light dark;
         }
-        a {
-            position: relative;
-        }
-        img {
-            margin: 5px;
-            border: solid 1px black;
+        @media (prefers-color-scheme: dark) {
+            .body {
+                background-color: #1c1c1e !important;
+            }
+            .text {
+                color: #f2f2f7 !important;
+            }
+            .link {
+                color: #8ab4f8 !important;
+            }
+            .thumb img, .video td {
+                border-color: #48484a !important;
+            }
         }
-        div.play-button {
-            position: absolute;
-            left: 50%;
-            top: 50%;
-            margin-left: -36px;
-            margin-top: -36px;
-            width: 73px;
-            height: 73px;
-            z-index: 1;
-            background-size: 73px 73px;
-            background-image: url(data:image/png;base64,%%%);
+        @media (max-width: 480px) {
+            .thumb {
+                width: 50% !important;
+                box-sizing: border-box;
+            }
+            .thumb img {
+                width: 100% !important;
+                height: auto !important;
+                box-sizing: border-box;
+            }
+            .video, .video td {
+                width: 100% !important;
+                height: auto !important;
+            }
+            .video a {
+                width: auto !important;
+                height: auto !important;
+                line-height: 0 !important;
+            }
+            %%%
         }"#
     .to_string()
-    .replace("%%%", PLAY_PNG);
+    .replace("%%%", &videos);
 
     // Cheesy CSS minifier
     Raw(s.lines().map(str::trim_start).collect())
 }
 
-/// The iCloud "video play button" png (73x73px @2), base64 encoded
-static PLAY_PNG: &str = "\
-    iVBORw0KGgoAAAANSUhEUgAAAJIAAACSCAQAAAAEclsFAAAACXBIWXMAAAsTAAALEwEAmpwYAAAF\
-    FWlUWHRYTUw6Y29tLmFkb2JlLnhtcAAAAAAAPD94cGFja2V0IGJlZ2luPSLvu78iIGlkPSJXNU0w\
-    TXBDZWhpSHpyZVN6TlRjemtjOWQiPz4gPHg6eG1wbWV0YSB4bWxuczp4PSJhZG9iZTpuczptZXRh\
-    LyIgeDp4bXB0az0iQWRvYmUgWE1QIENvcmUgNi4wLWMwMDIgNzkuMTY0NDYwLCAyMDIwLzA1LzEy\
-    LTE2OjA0OjE3ICAgICAgICAiPiA8cmRmOlJERiB4bWxuczpyZGY9Imh0dHA6Ly93d3cudzMub3Jn\
-    LzE5OTkvMDIvMjItcmRmLXN5bnRheC1ucyMiPiA8cmRmOkRlc2NyaXB0aW9uIHJkZjphYm91dD0i\
-    IiB4bWxuczp4bXA9Imh0dHA6Ly9ucy5hZG9iZS5jb20veGFwLzEuMC8iIHhtbG5zOmRjPSJodHRw\
-    Oi8vcHVybC5vcmcvZGMvZWxlbWVudHMvMS4xLyIgeG1sbnM6cGhvdG9zaG9wPSJodHRwOi8vbnMu\
-    YWRvYmUuY29tL3Bob3Rvc2hvcC8xLjAvIiB4bWxuczp4bXBNTT0iaHR0cDovL25zLmFkb2JlLmNv\
-    bS94YXAvMS4wL21tLyIgeG1sbnM6c3RFdnQ9Imh0dHA6Ly9ucy5hZG9iZS5jb20veGFwLzEuMC9z\
-    VHlwZS9SZXNvdXJjZUV2ZW50IyIgeG1wOkNyZWF0b3JUb29sPSJBZG9iZSBQaG90b3Nob3AgMjEu\
-    MiAoTWFjaW50b3NoKSIgeG1wOkNyZWF0ZURhdGU9IjIwMjAtMDctMjFUMTI6NTM6MTkrMDE6MDAi\
-    IHhtcDpNb2RpZnlEYXRlPSIyMDIwLTA3LTIxVDIyOjAyOjIzKzAxOjAwIiB4bXA6TWV0YWRhdGFE\
-    YXRlPSIyMDIwLTA3LTIxVDIyOjAyOjIzKzAxOjAwIiBkYzpmb3JtYXQ9ImltYWdlL3BuZyIgcGhv\
-    dG9zaG9wOkNvbG9yTW9kZT0iMSIgcGhvdG9zaG9wOklDQ1Byb2ZpbGU9IkRvdCBHYWluIDIwJSIg\
-    eG1wTU06SW5zdGFuY2VJRD0ieG1wLmlpZDo1MDc2ZTk1OC0wOWY3LTRkMmQtYjgyZC02ZDk3MWNh\
-    NjRkMzQiIHhtcE1NOkRvY3VtZW50SUQ9InhtcC5kaWQ6NTA3NmU5NTgtMDlmNy00ZDJkLWI4MmQt\
-    NmQ5NzFjYTY0ZDM0IiB4bXBNTTpPcmlnaW5hbERvY3VtZW50SUQ9InhtcC5kaWQ6NTA3NmU5NTgt\
-    MDlmNy00ZDJkLWI4MmQtNmQ5NzFjYTY0ZDM0Ij4gPHhtcE1NOkhpc3Rvcnk+IDxyZGY6U2VxPiA8\
-    cmRmOmxpIHN0RXZ0OmFjdGlvbj0iY3JlYXRlZCIgc3RFdnQ6aW5zdGFuY2VJRD0ieG1wLmlpZDo1\
-    MDc2ZTk1OC0wOWY3LTRkMmQtYjgyZC02ZDk3MWNhNjRkMzQiIHN0RXZ0OndoZW49IjIwMjAtMDct\
-    MjFUMTI6NTM6MTkrMDE6MDAiIHN0RXZ0OnNvZnR3YXJlQWdlbnQ9IkFkb2JlIFBob3Rvc2hvcCAy\
-    MS4yIChNYWNpbnRvc2gpIi8+IDwvcmRmOlNlcT4gPC94bXBNTTpIaXN0b3J5PiA8L3JkZjpEZXNj\
-    cmlwdGlvbj4gPC9yZGY6UkRGPiA8L3g6eG1wbWV0YT4gPD94cGFja2V0IGVuZD0iciI/PhqmUIkA\
-    ABYRSURBVHic7V17XFTV2n42Aw0YN2EIARXUQkFFVDDFI6iRFwpN1KysvETqsctXaWVyjqfPcyzN\
-    7OvrWKmRR0szb6ThXdLUAuWSiBcUIxAFLwwIDgcYZ3B/f+wLs9fec98zg+f3Pf+wZu29117rYa93\
-    rfWud70vReP/YQ7uAOXqOjwIbwBAINwNcvWoAwA04d8uqJMBaEG1nAcKDyEAvugMFUGNFPSogxq3\
-    cQf1uAUXfPoU7cwvSYGu6IYwdLWjjGpcw1VcQ5tstTID2hhJcv+7qC7oga4Ig0KmAttQjWuooG/I\
-    VB4BQ06cQBLli76Ihr/xO0KUo4MAIC7Yy6M9t0VXeBMAjtRe15oovgEXcJ6+I0dNDeE0kiglIhEt\
-    3bXGB8U/1CugV3C3kKBAL2/T5TRp6uuvXi+/WV5fcGt/reQt13ABZbQpMq2EU0ii/DAE0WKRnBTw\
-    bOSwyJ49vH1sK7dJ80dFXtmWsmP1okt6XEA+3WhbuSQcThIViHhEC4sNUb7ae2z/yJ4+fnYUbABN\
-    Y9kfB8+uvkR0RRoXUEDX2V++Q0migvEoHjHMUbplRD8VF9Xb3cPYM7ZDryu9tKNgRan2niD7Mk7R\
-    N+0r2WEkUb4YKSQoWbVwaGKcOZljL1qafs7/JD9HLci8jJ/tEecOIYlSYDCGGcqgtJDFowcPtKF+\
-    NqLo9AdHsq4bZOiRhyLaxtmUA0iiuuMxBLT/Tg9/fVT/fjbVzi6cPffZ0cwrBhn1+ImusqUkmUmi\
-    vPAYerf/Tg/PSInoZU2FdHdrawHg+o1WfXuup3tIFwAICvJ4wJrSKsuX7RMQdQk/0S3WlADITBIV\
-    jvF4kPsV7b0xNW6wJc9pGq9W36y/fLPwep76XJPpewf6xgXEhTwSHBzQLcyy0bGwaEb2hfZS/439\
-    9BUTt0tANpIoCsMwrP33+uHPjVd6mn5GrztzrrB8X8WPNo4+E4JTesT1GtDP3Fipbf1u/+xfDTLy\
-    kEdbIUNkIonyxnh0535N7/rRlFCTy9a2tvyioxeXniOGa5ugdFvSb1SfoXGUm6m7btQs3Lb5Gv+z\
-    CvtpM99sO2QhiQrHE/Bi0kq3nWOeSDZ1d2V5duGHJSbXYDYgRPlezIQh4T1M3bM3Z/Ih/t/Sgr2W\
-    djsZSKJikMw9Fu+3/XlTFS298OmRdZWWVc0WzIl4Y3RUtPHrVyqmbirgFis0cugSS0q1myRqGBK4\
-    dEZUxjTjk8XTxR8f/a7akkrZh+fCFo4aGGvsakvTsq3LSvmfuXSe+RLtIomi8BgGMGml2w/jxo82\
-    9prTxf+174R4GeowjAj43xTjRO0/MukA3+3O4CdzQtwOkigFUhDJpEOUx2c8HCn9ipqry7K/+MN0\
-    NRyB+T0zUkO7SV/7vSxxIy8Vy7DP9FzcZpIod0zmtEPR3odmh3WXuAn/1uw+Nv1nUxVwLDaPnJj0\
-    oKQiprpqzHp+9nQNO2m91F0MbCSJUiAV7Ew6wX/PvM4qqcLPnn1up7nJoaPRz/u7yf37S125rX5y\
-    TW4D+6Mc2ca/JltJSkEUk0oL2ThHWmmWmfVyrrHXOhdfJaSnSeU3aWas45fBpfQ+Y8/bRBI1AkOY\
-    1Izua+dIzatv1vxlZ6aVk39HIj38H5ODQ8X52ta56zZyi958+oT00zaQRMUjkUmlhXz3ihRFx3OT\
-    ssxV2/k4lpaYIM5tbZ7+Jf81HacLpJ4UkmRyYs8+EMVRlKzaNFeKom37OiJFQFLWNokO5dlp09zx\
-    QeyPRCrKfDlmSaICMYZJRXtvS5eaOH6ze9oR8y9yDaYd+Wa3ONfLe/NL0VxLxlCB5koxQxLlgQmM\
-    vjHQ49BsqRFt7Y4ZRvp1x8CME2t3iHM7qw7NDmQ0Ce6YQJnRKZj7kpI5jWPONPG8iKZXbJp30pKq\
-    uhLzTq7YJJ5hh3XPmcYmA2ByeW6GJKo/2KXjVwmxseRVmn5/w6Jii+rpYiwqfn+DODc29itOrEdT\
-    kvMqDiZIogLBrsymhc6aKL7+0eal5y2rpOux9PyKTeLcWROncZOE0aYkk1GSKAVSGWkU7rn6BYXI\
-    0OGfW++Pr4jDouJPt5B5CsXqF8KZ0dodqZRRYw7jX9IQsNzumqIKIi9+s/t1yflFR8abReKRThW0\
-    awqbDOSmy2IYIYkKxKNM6vMhYmm040DHHtGMYcaJHQfIvNjYzzlyHjXW5Yx9SY8zlkTxfi9NIC+V\
-    lU3Nsa2SrsfUnLIyMu+lCfHMHowCj0s/JU1SFMKYxNeTyBm27u68ndZX7njaI52sf8oRmLdTd1eY\
-    o/T8ehKbDJOef0uRpODUsxlR4n3Yld8ftcFqY0TC2cXfJvnIZedmB47WrfyezOvfL4MjZ7iU+JYi\
-    aRBjl+ajWCAa+LMOZlikSBdD6fl86pW3l7pg85tERknWQTJvwUT2H+iHQeInxCR5cFI+M4lchlRV\
-    Tj5sT/U6q/46s+LP0yQUGM7F5MNVlcKczqrMJDY5RLxIEZMUB08ACFFOFCn53/vB/gpG9Pr+rdyn\
-    Y220dJML4pZMHB2iBAB4Io68RpLkAXYv/9PhpMg+nivX9tCwIfmLticHOsCsy1J8V32c0KEqPT8d\
-    ziYHk98SSVIMlAAQ6JGaKLxQr07Llq+SHsop48rfdaWESsuuF5p9ITWR/ZaUiBFeEZJEcWLrk6Gk\
-    5mjnsTqdnJUE/PxdKaHqdDuPCXO8vJdz08pBQnWtkKRI+AKAj2LSSGEBtTdeOyVnFTm4UkK9dqqW\
-    MJWfNJId43wh2FEUksR+ZivjSTugXb/KYQ0iDVdJKO29Xb8Kc3z8PmEXY8IOZ0iSP2dKMzkJAtxW\
-    L8iXt4JCMBLqYydaWDJ47dRtQi49NYJNdDc8w2BIEitG5/YgV/17czUOP+7i579g+rXXZ0juCjsK\
-    2nt7iTFOFTSXs5AxUMMZksROzWcTKgNN4xsW2GHIgbDuG14vnJ7g75y3AcAbeY0Nwhy+9X3ahXc7\
-    SaGM0A70GBQrfOxgrtzjmikMHnjs3T3j2MHY4ajTHSTk0qBYVjr6gh9320liLWjf7kvaIy4kinE0\
-    3D2eSC5b9LlRFZi8eIfoJe4e73GzN96muJ2kh5k/KbHCh0pKrrQ6oG5m4O0z/+nrb71ilamzbbjS\
-    WkIs2cew9lfg386RpOI6W1Rv4SN7ih1UO7PoErr6zyUzkyWtV+QE2cKo3nyHY9/NkRTO/Jn3sLCz\
-    6bTLXboj0r/fgbcPpzpWQi0/rxX0FXePeWyvAjvScSSxQ+9YQjNXeMbxg79pKBTJSY6VUJq234gO\
-    x7PAmqwxJFGcurYfYeB39KLjKmc5HC2hyFbyLHRjpgEMSUHM2j/am1SzubazGcKREopsZWcVu5r0\
-    QBDAkcR+R89ECG8uLXV1ZxOCkVDhZo5mWA9NW2mpMCeNldEMMwxJwUzOMMJo/fTvclfHXigUyUkX\
-    F3+VoLTArsoakC3lmQgGOJLYj7hHmPDWghp5qyIPPDulp11d8E4fOcskW8ozoQIYkiiOpNAuwltz\
-    rqODIih4Rfql9AnBcpVHtpRnQgWKISmA260VaiM1d1xtbGwakX12LZBr0/Nck0ZwYtfLm9/VDWBI\
-    YhVsCYSCpPaWHK93JCg3+TY9ydbybPgLSIoMEN52y4knQ2yHXJueZGt5NvwYklgNczhBUs19QRIg\
-    z6Yn2VqeDV+GJF/mV3Bn4W1XG+x5qbMR0WvLG/ZIqBrCgUcQp+X3ZkhiJ2f+xJ5Ftey+ZBwL+yRU\
-    FUFSAEeSp4CkTsR/4brVB8VdD9slFNlang2WJC8im0V1s/Wv6giwTUKRrSVJYrU1XgRJlfcpSYAt\
-    m55ka70Ikv5DId+mpxvAuWEhNwDqnbhH4hhYs+l5gziQ/wDn/MOdIYkdDUhb7Y6lJrEVfv4Lph+d\
-    ZP4+LXGwgnfMoGBIYsloI0jpCBaO9qOxYdXmURYYnymJY380Z/vQxnQ1PfMt6XXCbynAo9HEUd77\
-    ATrt7qPzLDQZ6kJsNtzlbHT1cJHHUqegsOjlPcUaOUpyB6BlJgEtzUIDwIhOFffhdJJBdVXGLv6k\
-    rUWIICZALdyUQMuQ1MKs3pqb/QVL3LBOkMHPnvPR2JC5d+Fpa58KI0hq5khqYUhqJbJZhHhZX0FX\
-    wxopJATZWp6NVgFJDUT/DfO1/lWuRV7+/P22SiGytTwbLEnsav/mbeFt3fxte51rUFm+aPdWOzYu\
-    yNbybDQxJLGcXSHUTqGEEq7j4rZ69Z4l5+wrg2wtz4aGIYnVpJQRJD10X5Ckbd1+eP4v9q8OyNby\
-    bDQISMolPBQHPWTvix0N+t4vJ186cFkWbQXZWp6NBoakerRBARQ0tjQZbir5+Pbz7sibSmUX3862\
-    1csgiX7ePgLB3dLEujhrQz2zdqPBGurWEMbfySHyVEF+1N58N7N3plwUiVvKM6EGzS1L1Myed0V1\
-    r4cNb40PxWW5qiEfWps3HXj1pLzG9/GEHrOCO2qkBjilG/sfyasQ3jrwYXQw0PdO5MYsfzlX7vMJ\
-    ZEt5Jm4CHEksb1mE/6OoqI6lLim7+NSqxCx5BLUhfBRRhIUfz0Q1wJFUCx0AFGvIYwSL+spdIVsh\
-    txQyBNnK22p23q5DLcCRROMqc/kccSB8lKwGLraitTkzq9uqjxxmmphEGEHyLFQz3rbc+J8AgIOE\
-    xdegGFd3uLa2nGN9PpBfCrXDRxEXK8zhWWA/HY4k1ivkmt/1ghW00tO1He7suXErH892rLn9or5C\
-    PZpet4aze2NZ4UhSM8vcOl3pJWERT8Y6sH4mcaPm1S9jNhC+/x2AFOIwaVkZq2q5w80f2/fdWPYO\
-    nRE+EhMjvyGneTRpvtgW8snn5Y5/U7gn6YtlfzGb4CdE7SSxX9CH5/SEyuqDofJXzRT0ur05kctf\
-    cegxxHaQrdPrPuT0Cbx8biephutwvxULH0uR3dbVFIpOJ6148oDcvruNQemWQjhZLC7hOxuvnTJs\
-    PjvErif+h/4BHzjp4Gd11czP4jbzjledgOUD/QkVSSbno+5iu7NNQ5LOMn/WVqgJpckzf5K/eiSa\
-    NP/c2vUz63Y47MfTRMvUtWs5SXS2PdeQpAawVcz+RfhoaLdVFsWQsBWMFHK+b69Vg0nH1HzLq9DQ\
-    niuUNuxpnYxCLTEzeZbwNyEnnCuFDEG2qrU5o5BNCs4tCUkqY4T3dW0O8S2FhK0bBgfA+VKoHeuG\
-    hRAnIH7KZf9VdyBYnglJolHMJN75RUf8Zycnye3eoLFh1WbnSyEOgR6k9wOd9h3u0ygWekgmB/di\
-    aAHgQtMhwrVdgCorVb4q6rQ7DvRaYf0+q3zISg0gjoUdOsH6fddynwoHkiQdipjEm8dJuZSYMFUm\
-    5yt5+UOWT81x5kF6ElNDSSfU2tY3j7PJIpqomXiaWMjs6F5u3i3yjfyxpLd061BZ/swnCdvksfaw\
-    HeKW7D7CKvNaUUheE5OkAzuZTD9GquC6R+w04mTQMtxW/31Djy/t2WeVB9sf6x4hzLmtTuccBeWT\
-    35G0YelvaAIATdsqkYfPtLHLYsQPWAJt66bs8JX27rPKgWUxU8aTeat2s9ubTZCQk9Lu76OQwiTO\
-    z4om9Em6u2NXWe9E8XiaXJuI9mJU4MEFZMS4C+f7/otN7qNLAcvc35dymsqZWaT49nhgzWTrq+YI\
-    9b1tWDOZpEinnck576+mS0UPwLgd92HG3LSg8esfyUuRkdvNOPnuuNieHCmK2rM+m9+tNeIg0ghJ\
-    dB0nvl/JLy4mr04Zt3EEmXc/YOOIKePIvOJi3jd9vrEIusY1Rac4Y8CndjSITr69ONH5frPsxccD\
-    XxR5YG2of4qLIFAHo17rjJJEt2EP9ABwpXXeBtLGG1gw3dZxzjVYFrNgOpnX1jZvA7vJoMce40GD\
-    TOgcaTWOMqmtNd+IJBPw3gtLOszWpTks6fveC+Lcb37k52xHaRMbDiYVs3QJp+ed/SvpZQigqL+9\
-    eH98Tcti3p9JiWY6JSV86M5S0/EDzWmvD4OVR6O3VIvW626KxS86y2OW7fh8yOIXxRRVV43mYgbU\
-    GxvVOJghidZhLyOZ6nRj1mskAs3Pf3r9cHFux8H64fOfFudqGsesZxfYeuwVL0SEMLsPQt/CISZ1\
-    oen5dS0Slm+zJm01GlTR1dg6epbECaWWpufX8eHwDtFm/R9YFpVrCNh50bTQjfPv96hc2tYZX/AC\
-    +wQtub9ndVQugM4Hq6TfWjN3HamzBIDEhBtvpYeL812H9PAbb0lTNHcdT1GBNEUkbIgUOC0082Xp\
-    SIFrd3SUyEprhs6dIpXfpEn/iqdI7kiBAKXABPRk0kkBP8yRjjl55sy0nZdcvJTt3Wnr5AEDpK7c\
-    Vk9ad4xbPTgi5qQwemmsz96XpGNxa+5kH3dl9NJvEyeO9JE8FVNz7YmveY2oY6KXAmQc3BOzehkx\
-    PL1W9fcfHRlq2hjmRPx1QlcjLobLfx/xLyfEwQUAikIy56vaR7F9/NiRxl5T9NubB5wbUfl/xg2W\
-    CKfB4ODPU/fzRytKkOPAiMpsAQngNyozov7yrKdRJyqni5cc3uMQU1Ahngxe+rjxkNOtzf/YYhCb\
-    O4+2IAytHFHeB+Ax7rEE/y0vdDcx+Ls6ynvVlWe/5XeIafxEnzF+bztkIAmgIpDC+TgxHcgcACrL\
-    swv/u1jufbZAj7/FpsZFmHTRKQhc3oJ9dKVlZctCEkB540nwe+kzui+f0sXk1qVeV3D66MWl5+Sw\
-    olW6Lek3qk/8QNInhhA3ahbtMNhEr8Ye2uLjRDKRBFAUhnNR4ACl25fDnhsvtWQxhF535lxh+b4K\
-    W43WJwSn9IjrNaCfaXoAbevWg3MMgz+cwq/mhLUhZCMJAKhwPAH+iG+sT+aEwRapdTWNV6uvq8tr\
-    T1bnqs1NPnt3SlANDesVFKLqFkZGwZBG0en0Hw32iFuwl7YyILasJAGUN0bCwIf33B6LxpmWEyTu\
-    tqrrgHv6KzcAoE0PKJhYl13c3AFV4ANWWf9Wli8/sNbwINEl/Gx5N+MgM0kAQIVjNBcvFwDSw99+\
-    PNIFBy7KLq48LAiiXo8j1n5DDBxAEkApEIehhk490kIWj7as68mDotMfHMky9Duqx0kUmp5XG4dD\
-    SAIAyhcj8YhhTrJq4dDEOKl43nKipel44ccniZMDl3GMltCjWgqHkQQAVDAeFRLlo1jUN3VQVG9z\
-    45Et0OtKL2X/tvw8cZb7Mk7Rds7zHUoSAFAqDAFxyC5E+Wrvsf0je1o2OpmHprHsj4NnV18SmaOW\
-    It/U5pClcDhJAED5Ix59IToGlhTwbOSwyJ49pJV25tGk+aMir2xL2THx0rkN51FAN9hWLgmnkAQA\
-    lBK9EY0wqWvjg+IfekTVIygiNFBlfIHMoLW5Tl1ZU1F7WV1wa3+t5C3VuIBLtIxGzk4jiX2dH/oi\
-    Gia6Wbjnn1SAlyIuBAAeUAB32wCg8HpLG/CL2uRpt0ZcwHl7RLQ0nEwS+9IwhCOMi94gA2hcRTWu\
-    0DJFwSRhEUkOgju6ohu6wh4r3hpcw1Vcg9P8zTmbJA4KqBAIf/ghECqxeCfQBjXq0IgG1EENpzt2\
-    pEHJ3bH+E/F/CZ+cmLJGKw8AAAAASUVORK5CYII=";
+/// Inline styles for each element.
+mod style {
+    /// The font (and text layout) for any element containing text.
+    macro_rules! font {
+        () => {
+            "font-family: -apple-system, BlinkMacSystemFont, Roboto, sans-serif; \
+             color: #000000; text-align: center; "
+        };
+    }
+
+    pub static BODY: &str = "margin: 0; padding: 0; background-color: #ffffff;";
+    pub static OUTER: &str = "padding: 16px 8px;";
+    pub static CONTAINER: &str = "max-width: 640px; margin: 0 auto;";
+    pub static TEXT: &str = concat!(font!(), "font-size: 16px; margin: 0 0 16px 0;");
+    pub static EMPH: &str = concat!(
+        font!(),
+        "font-size: 19px; font-weight: bold; margin: 0 0 16px 0;"
+    );
+    pub static LINK: &str = "color: #0645ad; text-decoration: underline;";
+    pub static GROUP: &str = concat!(
+        font!(),
+        "font-size: 16px; font-weight: bold; margin: 16px 0 0 0;"
+    );
+    pub static DAY: &str = concat!(
+        font!(),
+        "font-size: 19px; font-weight: bold; margin: 32px 0 0 0;"
+    );
+    pub static COMMENT: &str =
+        concat!(font!(), "font-size: 16px; font-style: italic; margin: 0;");
+    pub static THUMB: &str = "display: inline-block; vertical-align: top; padding: 5px;";
+    pub static IMG: &str = "display: block; border: 1px solid #000000;";
+    pub static LARGE_IMG: &str =
+        "display: block; max-width: 100%; height: auto; border: 1px solid #000000;";
+    pub static CAPTION: &str =
+        concat!(font!(), "font-size: 13px; margin: 2px auto 0 auto;");
+    pub static CONTACT_SHEET: &str =
+        "display: block; max-width: 100%; height: auto; border: 0;";
+    pub static VIDEO: &str = "border: 1px solid #000000; background-color: #000000; \
+                              background-position: center; background-size: cover;";
+    pub static VIDEO_LINK: &str = "display: block; text-decoration: none;";
+    pub static PLAY: &str = "display: inline-block; width: 48px; height: 48px; \
+                             line-height: 48px; border-radius: 24px; \
+                             background-color: #000000; color: #ffffff; \
+                             font-family: Arial, sans-serif; font-size: 22px; \
+                             text-align: center; vertical-align: middle;";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    /// HTML with a line break between tags, for readable snapshots.
+    fn lines(html: String) -> String {
+        html.replace("><", ">\n<")
+    }
+
+    /// A whole email: a captioned photo and a video (with its VML fallback for
+    /// Outlook), uploaded together with a comment.
+    #[test]
+    fn email() {
+        let config = testing::config(json!({}));
+        let mut photo = testing::photo("G1");
+        photo.caption = Some("Rex & Fido \"playing\"".to_string());
+        photo.batch_comment = Some("At the park".to_string());
+        let mut video = testing::video("G2");
+        video.width = 320;
+        let assets = [&photo, &video];
+        let html = build(
+            &config,
+            &assets,
+            assets.to_vec(),
+            testing::thumbnail_urls(&assets),
+            chrono_tz::Europe::London,
+            None,
+        );
+        insta::assert_snapshot!(lines(html));
+    }
+
+    /// Just the content, with assets in separate groups (one undated), and
+    /// more assets than shown.
+    #[test]
+    fn fragment_groups() {
+        let config = testing::config(json!({}));
+        let first = testing::photo("G1");
+        let not_shown = testing::photo("G2");
+        let mut second = testing::photo("G3");
+        second.contributor = "Bob".to_string();
+        second.batch_guid = Some("B2".to_string());
+        second.batch_date_created =
+            Some(Utc.with_ymd_and_hms(2024, 5, 6, 23, 30, 0).unwrap());
+        let mut undated = testing::video("G4");
+        undated.contributor = String::new();
+        undated.batch_guid = None;
+        undated.batch_date_created = None;
+        let assets = [&first, &not_shown, &second, &undated];
+        let shown = vec![&first, &second, &undated];
+        let html = fragment(
+            &config,
+            &assets,
+            shown,
+            &testing::thumbnail_urls(&assets),
+            chrono_tz::Europe::London,
+            None,
+        );
+        insta::assert_snapshot!(lines(html));
+    }
+}
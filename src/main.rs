@@ -2,16 +2,39 @@
 
 //! Check a public iCloud Shared Photo library, and if any new photos/videos
 //! have been posted since last time we ran, send an email summarising the
-//! new content.
+//! new content (and tell any other configured notifiers).
 
+mod atom;
+mod dkim;
 mod email;
+mod encrypt;
+mod exec;
 mod fetch;
+mod gallery;
 mod html;
+mod matrix;
+mod notify;
+mod push;
+mod report;
+mod s3;
+mod schedule;
+mod select;
+mod sheet;
+mod state;
+mod telegram;
+#[cfg(test)]
+mod testing;
 mod types;
+mod unsubscribe;
 mod utils;
+mod webdav;
+mod webhook;
 
-use clap::Parser;
-use std::collections::HashSet;
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use report::ReportFormat;
+use state::{Deliveries, Delivery, Pending, State};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use types::*;
 use utils::OrDie;
 
@@ -22,6 +45,31 @@ pub struct Opts {
     /// Mandatory JSON configuration file
     #[clap(short, long)]
     pub config: String,
+
+    /// How to report on a check ("json" prints a summary on stdout)
+    #[clap(long, arg_enum, value_name = "FORMAT", default_value = "text")]
+    pub report: ReportFormat,
+
+    /// Optionally, do something other than check for updates
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Alternatives to the default of checking for updates
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the HTTP server that handles one-click unsubscribe links
+    ServeUnsubscribe,
+
+    /// Render the whole album as a static HTML gallery
+    Gallery {
+        /// Directory to write the gallery to (created if need be)
+        dir: String,
+
+        /// How many thumbnails to show on each index page
+        #[clap(long, default_value = "60")]
+        per_page: usize,
+    },
 }
 
 /// Overall program logic:
@@ -29,10 +77,17 @@ pub struct Opts {
 ///  - Load the required config JSON file
 ///  - Load all the local state
 ///  - Fetch the state from iCloud
-///  - If there are new photos in iCloud, get all the required info (thumbnail
-///    URL + size, click-through URL) and compose an HTML document displaying it
-///  - Send an email
+///  - Queue up any new photos in iCloud to be emailed, and tell any other
+///    notifiers about them (and any that have disappeared) straight away
 ///  - Update the local state for which photo/video assets have been seen
+///    (saving it before emailing, in case that fails)
+///  - For each set of recipients due an email (now, or on their digest
+///    schedule), get all the required info (thumbnail URL + size,
+///    click-through URL), compose an HTML document displaying it, and send an
+///    email
+///  - Update the local state for which assets have been sent
+///  - If asked for a report, print it, and exit with a code saying how that
+///    went (see `report::Outcome`)
 fn main() {
     // Parse command-line options
     let opts = Opts::parse();
@@ -41,23 +96,50 @@ fn main() {
     let config: Config = utils::load_json(&opts.config)
         .or_die(format!("successfully parse file {}", opts.config));
 
-    // Load the database of previously seen Guids if available.
-    let seen_guids: HashSet<Guid> = utils::load_json(&config.db_file).unwrap_or_default();
+    // Handle any alternative command.
+    match opts.command {
+        Some(Command::ServeUnsubscribe) => {
+            unsubscribe::serve(&config).or_die("serve unsubscribe requests");
+            return;
+        }
+        Some(Command::Gallery { dir, per_page }) => {
+            gallery::generate(&config, &dir, per_page)
+                .or_die(format!("generate a gallery in {}", dir));
+            return;
+        }
+        None => {}
+    }
+    email::check_config(&config);
+    if opts.report == ReportFormat::Json {
+        report::start(&config);
+    }
+
+    // Load the state from previous runs if available (holding on to it until
+    // it's saved), and index the previously seen Guids for lookup.
+    let state_lock =
+        state::lock(&config.db_file).or_die(format!("lock file {}", config.db_file));
+    let mut state = state::load(&config.db_file, &state_lock)
+        .or_die(format!("load file {}", config.db_file));
+    let seen_guids: HashSet<Guid> = state.seen_guids.iter().cloned().collect();
 
     // Fetch all available assets from iCloud.
-    let all_assets = fetch::all_assets(&config.album_id)
-        .or_die(format!("download {}", config.album_id));
+    let all_assets =
+        fetch::all_assets(&config).or_die(format!("download {}", config.album_id));
 
     // Just the Guids, indexed for lookup
     let new_guid_set: HashSet<&Guid> = all_assets.iter().map(|a| &a.guid).collect();
 
     // Minority case: see if any previously-seen assets have disappeared.
-    seen_guids
+    let removed_guids: Vec<&Guid> = seen_guids
         .iter()
         .filter(|old_guid| !new_guid_set.contains(old_guid))
-        .for_each(|guid| {
-            eprintln!("Warning: DB has seen {} but this has disappeared", guid)
-        });
+        .collect();
+    removed_guids.iter().for_each(|guid| {
+        report::warn(format_args!(
+            "DB has seen {} but this has disappeared",
+            guid
+        ))
+    });
 
     // Mainline case: see which assets have not been previously seen. We've
     // carefully preserved order, so that if there are any of these, they are in
@@ -67,26 +149,176 @@ fn main() {
         .filter(|asset| !seen_guids.contains(&asset.guid))
         .collect();
 
-    // If there's nothing to do, then just exit now
-    let num_new_assets = new_assets.len();
-    if num_new_assets == 0 {
-        return;
-    }
+    // And see which have been edited since last time (going by the best
+    // version, if we knew that before).
+    let checksums: BTreeMap<Guid, Checksum> = all_assets
+        .iter()
+        .map(|a| (a.guid.clone(), a.checksum_best.clone()))
+        .collect();
+    let num_updated = all_assets
+        .iter()
+        .filter(|asset| match state.seen_checksums.get(&asset.guid) {
+            Some(checksum) => *checksum != asset.checksum_best,
+            None => false,
+        })
+        .count();
+    report::record(|report| {
+        report.assets = report::Counts {
+            total: all_assets.len(),
+            new: new_assets.len(),
+            updated: num_updated,
+            removed: removed_guids.len(),
+        }
+    });
 
-    // Fetch thumbnail URLs for all the new assets.
-    let new_guids: Vec<&Guid> = new_assets.iter().map(|a| &a.guid).collect();
-    let thumbnail_urls = fetch::thumbnail_urls(&new_guids, &config)
-        .or_die(format!("fetch data for {} new guids", new_guids.len()));
+    // Tell the notifiers other than email about any changes (or let them
+    // retry anything that failed last time).
+    let any_removed = !removed_guids.is_empty();
+    let notified =
+        notify::send(&config, &all_assets, &new_assets, removed_guids, &mut state)
+            .or_die("notify");
 
-    // Build the HTML for all new things.
-    let html = html::build(&config, new_assets, thumbnail_urls);
+    // Queue up the new assets to be emailed.
+    let now = Utc::now();
+    state.pending.extend(new_assets.iter().map(|asset| Pending {
+        guid: asset.guid.clone(),
+        first_seen: now,
+    }));
 
-    // Send it over email
-    email::send(&config, html).or_die("send email");
-    println!("Sent email for {} new assets", num_new_assets);
+    // Update the state with all the Guids (and checksums) now seen, and save it
+    // before emailing, so that if that fails the notifiers aren't told all over
+    // again next time (but the emails are still pending).
+    let changed = !new_assets.is_empty()
+        || any_removed
+        || notified
+        || checksums != state.seen_checksums;
+    if changed {
+        state.seen_guids = all_assets.iter().map(|a| a.guid.clone()).collect();
+        state.seen_checksums = checksums;
+        state::save(&state, &config.db_file, &state_lock)
+            .or_die(format!("save file {}", config.db_file));
+    }
+
+    // Work out who is due an email now, and with what (if uploads have
+    // settled down). If there's nothing to do, then just exit now.
+    let assets_by_guid: HashMap<&Guid, &Asset> =
+        all_assets.iter().map(|a| (&a.guid, a)).collect();
+    let batches = if schedule::settled(&config, &state, &assets_by_guid, now) {
+        schedule::due(&config, &state, now)
+    } else {
+        vec![]
+    };
+    if batches.is_empty() {
+        std::process::exit(report::finish());
+    }
 
-    // Update the database of seen Guids.
-    let all_guids: Vec<&Guid> = all_assets.iter().map(|a| &a.guid).collect();
-    utils::save_json(&all_guids, &config.db_file)
+    // Email each batch of recipients (skipping any assets that have since
+    // disappeared).
+    let mut emailed: HashSet<&Guid> = HashSet::new();
+    for batch in batches {
+        let assets: Vec<&Asset> = batch
+            .guids
+            .iter()
+            .filter_map(|g| assets_by_guid.get(g).copied())
+            .collect();
+        let mut deliveries = Deliveries::new();
+        if !assets.is_empty() {
+            emailed.extend(assets.iter().map(|a| &a.guid));
+            // If the email can't be sent at all, that counts as failing for
+            // everyone in the batch, but the other batches still go ahead.
+            deliveries = email_batch(&config, opts.report, &mut state, &batch, assets)
+                .unwrap_or_else(|err| {
+                    let delivery = Delivery::Failed(err.to_string());
+                    batch
+                        .recipients
+                        .iter()
+                        .map(|recipient| (recipient.addr.clone(), delivery.clone()))
+                        .collect()
+                });
+            for (recipient, delivery) in &deliveries {
+                report::emailed(recipient, delivery);
+            }
+            state.deliveries.extend(deliveries.clone());
+        }
+
+        // Anyone it couldn't be sent to is still owed the assets, to try again
+        // next time.
+        for recipient in batch.recipients {
+            if !matches!(deliveries.get(&recipient.addr), Some(Delivery::Failed(_))) {
+                state.last_sent.insert(recipient.addr.clone(), now);
+            }
+        }
+    }
+    schedule::prune(&config, &mut state);
+    report::record(|report| report.emailed = emailed.len());
+    state::save(&state, &config.db_file, &state_lock)
         .or_die(format!("save file {}", config.db_file));
+    std::process::exit(report::finish());
+}
+
+/// Email some assets to a batch of recipients, returning the outcome for each
+/// (or an error if it couldn't be sent to any of them).
+fn email_batch(
+    config: &Config,
+    report_format: ReportFormat,
+    state: &mut State,
+    batch: &schedule::Batch<'_>,
+    assets: Vec<&Asset>,
+) -> Result<Deliveries, Box<dyn std::error::Error>> {
+    // Choose which of the assets to show, and fetch their thumbnail URLs.
+    let num_assets = assets.len();
+    let shown_assets = select::thumbnails(config, &assets);
+    let shown_guids: Vec<&Guid> = shown_assets.iter().map(|a| &a.guid).collect();
+    let thumbnail_urls = fetch::thumbnail_urls(&shown_guids, config).map_err(|err| {
+        format!(
+            "fetching data for {} new guids failed: {}",
+            shown_guids.len(),
+            err
+        )
+    })?;
+
+    // Put together a contact sheet, if configured (but carry on without one if
+    // that fails). Any thumbnails iCloud didn't give a URL for are left blank.
+    let contact_sheet = config.contact_sheet.as_ref().and_then(|sheet_config| {
+        let urls: Vec<Option<&Url>> = shown_assets
+            .iter()
+            .map(|a| thumbnail_urls.get(&a.checksum))
+            .collect();
+        let found: Vec<&Url> = urls.iter().flatten().copied().collect();
+        let mut downloaded = fetch::images(&found).into_iter();
+        let images = urls
+            .iter()
+            .map(|url| url.and_then(|_| downloaded.next().flatten()))
+            .collect();
+        sheet::build(config, sheet_config, &shown_assets, images)
+            .map_err(|err| {
+                report::warn(format_args!("unable to build contact sheet: {}", err))
+            })
+            .ok()
+    });
+
+    // Build the HTML (and plain text) for all new things.
+    let plaintext = html::plaintext(config, &shown_assets);
+    let html = html::build(
+        config,
+        &assets,
+        shown_assets,
+        thumbnail_urls,
+        batch.time_zone,
+        contact_sheet.as_ref(),
+    );
+
+    // Send it over email
+    let deliveries = email::send(
+        config,
+        &batch.recipients,
+        html,
+        plaintext,
+        contact_sheet.as_ref(),
+        state,
+    )?;
+    if report_format == ReportFormat::Text {
+        println!("Sent email for {} new assets", num_assets);
+    }
+    Ok(deliveries)
 }
@@ -0,0 +1,418 @@
+//! Matrix notifier: post updates to a room
+//!
+//! Each update is a text message (linking to the album), followed by a few of
+//! the thumbnails, uploaded to the homeserver's media repository and posted as
+//! `m.image` events. This uses just the client-server API, authenticated with
+//! an access token, so the account needs to have joined the room already.
+
+use crate::fetch;
+use crate::html;
+use crate::notify::{Notifier, Update};
+use crate::report;
+use crate::state::State;
+use crate::types::*;
+use crate::utils;
+use serde_json::json;
+use std::cell::Cell;
+use std::fmt;
+use std::io::Cursor;
+use surf::http::{mime, Mime};
+
+/// A configured Matrix room.
+pub struct Matrix<'a> {
+    config: &'a MatrixConfig,
+    access_token: String,
+    /// Events sent so far, to make each transaction ID unique.
+    sent: Cell<u32>,
+}
+
+impl<'a> Matrix<'a> {
+    /// Set up a Matrix room, loading the access token.
+    pub fn new(config: &'a MatrixConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let access_token =
+            String::from_utf8(utils::load_secret(&config.access_token_file)?)?;
+        Ok(Matrix {
+            config,
+            access_token,
+            sent: Cell::new(0),
+        })
+    }
+
+    /// The URL for a client-server API endpoint.
+    fn endpoint(&self, path: &str) -> String {
+        format!(
+            "{}/_matrix/{}",
+            self.config.homeserver.trim_end_matches('/'),
+            path
+        )
+    }
+
+    /// Send an authenticated request, returning the JSON response.
+    fn request(
+        &self,
+        request: surf::RequestBuilder,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let request =
+            request.header("Authorization", format!("Bearer {}", self.access_token));
+        let (status, body) = smol::block_on(async {
+            let mut response = request.send().await?;
+            let body = response.body_string().await?;
+            Ok::<_, surf::Error>((response.status(), body))
+        })
+        .map_err(|err| err.to_string())?;
+        let json: Option<serde_json::Value> = serde_json::from_str(&body).ok();
+        if !status.is_success() {
+            // Matrix errors look like {"errcode": "M_FORBIDDEN", "error": "..."}
+            let error = json
+                .as_ref()
+                .and_then(|json| {
+                    let errcode = json["errcode"].as_str()?;
+                    let error = json["error"].as_str().unwrap_or_default();
+                    Some(format!("{} {}", errcode, error))
+                })
+                .unwrap_or(body);
+            return Err(format!("HTTP status {}: {}", status, error).into());
+        }
+        json.ok_or_else(|| "invalid JSON response".into())
+    }
+
+    /// Post a message to the room.
+    fn post(&self, content: serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+        let room_id: String =
+            url::form_urlencoded::byte_serialize(self.config.room_id.as_bytes())
+                .collect();
+        let sent = self.sent.get();
+        self.sent.set(sent + 1);
+        let txn_id = format!(
+            "icloud-biff.{}.{}",
+            chrono::Utc::now().timestamp_millis(),
+            sent
+        );
+        let url = self.endpoint(&format!(
+            "client/v3/rooms/{}/send/m.room.message/{}",
+            room_id, txn_id
+        ));
+        self.request(surf::put(url).body_json(&content)?)?;
+        Ok(())
+    }
+
+    /// Upload an image to the media repository, returning its MXC URI.
+    fn upload(
+        &self,
+        data: Vec<u8>,
+        mimetype: &str,
+        filename: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let filename: String =
+            url::form_urlencoded::byte_serialize(filename.as_bytes()).collect();
+        let url = self.endpoint(&format!("media/v3/upload?filename={}", filename));
+        let mimetype: Mime = mimetype.parse().unwrap_or(mime::BYTE_STREAM);
+        let response = self.request(surf::post(url).content_type(mimetype).body(data))?;
+        response["content_uri"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| "no content_uri in upload response".into())
+    }
+}
+
+impl Notifier for Matrix<'_> {
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        _state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // There's no point pinging people about things disappearing.
+        if update.new_assets.is_empty() {
+            return Ok(());
+        }
+
+        self.post(text_message(update))?;
+
+        // Then the first few thumbnails (skipping any that can't be
+        // downloaded, or aren't images we recognise).
+        let shown: Vec<_> = update
+            .new_assets
+            .iter()
+            .filter(|new| new.thumbnail_url.is_some())
+            .take(self.config.max_thumbnails)
+            .collect();
+        let urls: Vec<&Url> = shown.iter().filter_map(|new| new.thumbnail_url).collect();
+        for (new, data) in shown.iter().zip(fetch::images(&urls)) {
+            let data = match data {
+                Some(data) => data,
+                None => continue,
+            };
+            let (format, (width, height)) = match image_details(&data) {
+                Ok(details) => details,
+                Err(err) => {
+                    report::warn(format_args!(
+                        "unable to read thumbnail of {}: {}",
+                        new.guid, err
+                    ));
+                    continue;
+                }
+            };
+            let mimetype = format.to_mime_type();
+            let filename = format!("{}.{}", new.guid, format.extensions_str()[0]);
+            let size = data.len();
+            let mxc = self.upload(data, mimetype, &filename)?;
+            self.post(image_message(
+                new.asset,
+                &mxc,
+                mimetype,
+                (width, height),
+                size,
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+/// The format and dimensions of an image.
+fn image_details(data: &[u8]) -> image::ImageResult<(image::ImageFormat, (u32, u32))> {
+    let format = image::guess_format(data)?;
+    let dimensions =
+        image::ImageReader::with_format(Cursor::new(data), format).into_dimensions()?;
+    Ok((format, dimensions))
+}
+
+/// The text message for an update, linking to the album.
+fn text_message(update: &Update<'_>) -> serde_json::Value {
+    let album = &update.album;
+    let counts = html::counts(&update.assets());
+    json!({
+        "msgtype": "m.text",
+        "body": format!("New in {}: {} {}", album.name, counts, album.url),
+        "format": "org.matrix.custom.html",
+        "formatted_body": format!(
+            "New in <a href=\"{}\">{}</a>: {}",
+            utils::escape_html(&album.url),
+            utils::escape_html(album.name),
+            counts
+        ),
+    })
+}
+
+/// The message for an asset's thumbnail, once uploaded to `mxc`, with the
+/// details of the image.
+fn image_message(
+    asset: &Asset,
+    mxc: &str,
+    mimetype: &str,
+    (width, height): (u32, u32),
+    size: usize,
+) -> serde_json::Value {
+    json!({
+        "msgtype": "m.image",
+        "body": html::alt_text(asset),
+        "url": mxc,
+        "info": {"mimetype": mimetype, "w": width, "h": height, "size": size},
+    })
+}
+
+impl fmt::Display for Matrix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Matrix room {}", self.config.room_id)
+    }
+}
+
+// Not derived, to keep the access token out of any debug output.
+impl fmt::Debug for Matrix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Matrix")
+            .field("config", self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::collections::HashMap;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    /// A request to the stand-in homeserver.
+    #[derive(Debug)]
+    struct Request {
+        method: String,
+        url: String,
+        authorization: Option<String>,
+        content_type: Option<String>,
+        body: Vec<u8>,
+    }
+
+    /// Notify a room on a stand-in homeserver (which also serves the
+    /// thumbnails, one of which isn't really an image).
+    #[test]
+    fn homeserver() {
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let base = format!("http://{}", server.server_addr());
+        let mut png = vec![];
+        image::RgbImage::new(4, 3)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let handler = {
+            let server = server.clone();
+            let png = png.clone();
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let header = |name: &'static str| {
+                        request
+                            .headers()
+                            .iter()
+                            .find(|header| header.field.equiv(name))
+                            .map(|header| header.value.to_string())
+                    };
+                    let mut recorded = Request {
+                        method: request.method().to_string(),
+                        url: request.url().to_string(),
+                        authorization: header("Authorization"),
+                        content_type: header("Content-Type"),
+                        body: vec![],
+                    };
+                    request.as_reader().read_to_end(&mut recorded.body).unwrap();
+                    let body = match recorded.url.as_str() {
+                        "/G1.png" => b"not an image".to_vec(),
+                        "/G2.png" => png.clone(),
+                        url if url.starts_with("/_matrix/media/v3/upload") => {
+                            br#"{"content_uri": "mxc://example.com/abc"}"#.to_vec()
+                        }
+                        _ => br#"{"event_id": "$event"}"#.to_vec(),
+                    };
+                    sender.send(recorded).unwrap();
+                    request
+                        .respond(tiny_http::Response::from_data(body))
+                        .unwrap();
+                }
+            })
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let token_file = dir.path().join("token");
+        std::fs::write(&token_file, "secret-token\n").unwrap();
+        let matrix_config: MatrixConfig = serde_json::from_value(json!({
+            "homeserver": base,
+            "access-token-file": token_file,
+            "room-id": "!room:example.com",
+        }))
+        .unwrap();
+        let matrix = Matrix::new(&matrix_config).unwrap();
+
+        let config = testing::config(json!({}));
+        let assets = [testing::photo("G1"), testing::photo("G2")];
+        let new_assets: Vec<&Asset> = assets.iter().collect();
+        let thumbnail_urls: HashMap<Checksum, Url> = assets
+            .iter()
+            .map(|asset| {
+                let url = Url(format!("{}/{}.png", base, asset.guid));
+                (asset.checksum.clone(), url)
+            })
+            .collect();
+        let update = Update::new(&config, &assets, &new_assets, &thumbnail_urls, vec![]);
+        matrix.notify(&update, &mut State::default()).unwrap();
+        server.unblock();
+        handler.join().unwrap();
+
+        // The text, then just the thumbnail that's really an image.
+        let requests: Vec<Request> = receiver
+            .try_iter()
+            .filter(|request| request.url.starts_with("/_matrix/"))
+            .collect();
+        let send = "/_matrix/client/v3/rooms/%21room%3Aexample.com/send/m.room.message/";
+        let summary: Vec<(&str, &str)> = requests
+            .iter()
+            .map(|request| {
+                let url = match request.url.strip_prefix(send) {
+                    Some(_) => send,
+                    None => &request.url,
+                };
+                (request.method.as_str(), url)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("PUT", send),
+                ("POST", "/_matrix/media/v3/upload?filename=G2.png"),
+                ("PUT", send),
+            ]
+        );
+        for request in &requests {
+            assert_eq!(
+                request.authorization.as_deref(),
+                Some("Bearer secret-token")
+            );
+        }
+        let txn_ids: Vec<&str> = [&requests[0], &requests[2]]
+            .iter()
+            .map(|request| &request.url[send.len()..])
+            .collect();
+        assert_ne!(txn_ids[0], txn_ids[1]);
+
+        let body = |request: &Request| -> serde_json::Value {
+            serde_json::from_slice(&request.body).unwrap()
+        };
+        assert_eq!(body(&requests[0]), text_message(&update));
+        assert_eq!(requests[1].content_type.as_deref(), Some("image/png"));
+        assert_eq!(requests[1].body, png);
+        assert_eq!(
+            body(&requests[2]),
+            image_message(
+                &assets[1],
+                "mxc://example.com/abc",
+                "image/png",
+                (4, 3),
+                png.len()
+            )
+        );
+    }
+
+    #[test]
+    fn text() {
+        let config = testing::config(json!({"album-name": "Rex & Fido"}));
+        let assets = [
+            testing::photo("G1"),
+            testing::photo("G2"),
+            testing::video("G3"),
+        ];
+        let new_assets: Vec<&Asset> = assets.iter().collect();
+        let thumbnail_urls = HashMap::new();
+        let update = Update::new(&config, &assets, &new_assets, &thumbnail_urls, vec![]);
+        assert_eq!(
+            text_message(&update),
+            json!({
+                "msgtype": "m.text",
+                "body": "New in Rex & Fido: 2 photos and 1 video \
+                         https://www.icloud.com/sharedalbum/#B0zAxqIORGhwx3u",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "New in <a href=\"https://www.icloud.com/sharedalbum/\
+                                   #B0zAxqIORGhwx3u\">Rex &amp; Fido</a>: 2 photos and \
+                                   1 video",
+            })
+        );
+    }
+
+    #[test]
+    fn image() {
+        let mut photo = testing::photo("G1");
+        let mxc = "mxc://example.com/abc";
+        assert_eq!(
+            image_message(&photo, mxc, "image/jpeg", (240, 180), 1234),
+            json!({
+                "msgtype": "m.image",
+                "body": "Photo",
+                "url": "mxc://example.com/abc",
+                "info": {"mimetype": "image/jpeg", "w": 240, "h": 180, "size": 1234},
+            })
+        );
+        photo.caption = Some("Rex at the park".to_string());
+        assert_eq!(
+            image_message(&photo, mxc, "image/jpeg", (240, 180), 1234)["body"],
+            "Rex at the park"
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Notifiers other than email
+//!
+//! Each is sent a structured `Update` whenever new assets are spotted (or old
+//! ones disappear). Email isn't one of these, since it has its own
+//! per-recipient scheduling (digests, quiet hours, settling): notifiers are
+//! told about everything straight away.
+
+use crate::atom::Atom;
+use crate::email;
+use crate::exec::Exec;
+use crate::fetch;
+use crate::matrix::Matrix;
+use crate::push::{Gotify, Ntfy};
+use crate::report;
+use crate::s3::S3;
+use crate::state::State;
+use crate::telegram::Telegram;
+use crate::types::*;
+use crate::webdav::Webdav;
+use crate::webhook::Webhook;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// Something that can be told about updates. It's `Display`ed (eg "webhook
+/// https://example.com/hook") in any warnings.
+pub trait Notifier: Display {
+    /// Tell whatever's on the other end about an update. Anything that needs
+    /// remembering for next time can go in the `state`.
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Whether there's anything left over in the `state` to try again, in
+    /// which case it's told about updates even when nothing has changed.
+    fn has_retries(&self, _state: &State) -> bool {
+        false
+    }
+
+    /// Whether failing should abort the whole run, rather than just being
+    /// warned about.
+    fn aborts_on_failure(&self) -> bool {
+        false
+    }
+}
+
+/// Set up one notifier.
+fn notifier<'a>(
+    config: &'a Config,
+    notifier_config: &'a NotifierConfig,
+) -> Result<Box<dyn Notifier + 'a>, Box<dyn std::error::Error>> {
+    match notifier_config {
+        NotifierConfig::Webhook(webhook) => Ok(Box::new(Webhook::new(webhook)?)),
+        NotifierConfig::Matrix(matrix) => Ok(Box::new(Matrix::new(matrix)?)),
+        NotifierConfig::Telegram(telegram) => Ok(Box::new(Telegram::new(telegram)?)),
+        NotifierConfig::Ntfy(ntfy) => Ok(Box::new(Ntfy::new(ntfy)?)),
+        NotifierConfig::Gotify(gotify) => Ok(Box::new(Gotify::new(gotify)?)),
+        NotifierConfig::Atom(atom) => Ok(Box::new(Atom::new(config, atom))),
+        NotifierConfig::Exec(exec) => Ok(Box::new(Exec::new(config, exec))),
+        NotifierConfig::Webdav(webdav) => Ok(Box::new(Webdav::new(config, webdav)?)),
+        NotifierConfig::S3(s3) => Ok(Box::new(S3::new(config, s3)?)),
+    }
+}
+
+/// Describe a notifier from its config, the same as the notifier itself would
+/// be `Display`ed, for when it couldn't be set up.
+fn describe(notifier_config: &NotifierConfig) -> String {
+    match notifier_config {
+        NotifierConfig::Webhook(webhook) => format!("webhook {}", webhook.url),
+        NotifierConfig::Matrix(matrix) => format!("Matrix room {}", matrix.room_id),
+        NotifierConfig::Telegram(telegram) => {
+            format!("Telegram chat {}", telegram.chat_id)
+        }
+        NotifierConfig::Ntfy(ntfy) => format!("ntfy topic {}", ntfy.topic),
+        NotifierConfig::Gotify(gotify) => format!("Gotify server {}", gotify.server),
+        NotifierConfig::Atom(atom) => format!("Atom feed {}", atom.file),
+        NotifierConfig::Exec(exec) => format!("command {}", exec.command.join(" ")),
+        NotifierConfig::Webdav(webdav) => format!("WebDAV folder {}", webdav.url),
+        NotifierConfig::S3(s3) => format!(
+            "S3 bucket {}/{}",
+            s3.endpoint.trim_end_matches('/'),
+            s3.bucket
+        ),
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+///
+/// # An update to the album, as sent to notifiers
+///
+/// This is serialized as-is for webhooks, so any change here is a change to
+/// the documented payload. The `subject` (as for emails), all the
+/// `thumbnail_urls` (including high-DPI ones), and all the `album_assets` (for
+/// looking up any left over from previous runs) aren't part of it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Update<'a> {
+    #[serde(skip)]
+    pub subject: String,
+    #[serde(skip)]
+    pub thumbnail_urls: &'a HashMap<Checksum, Url>,
+    #[serde(skip)]
+    pub album_assets: &'a [Asset],
+    pub album: Album<'a>,
+    pub new_assets: Vec<NewAsset<'a>>,
+    pub removed_assets: Vec<&'a Guid>,
+}
+
+/// The album an update is for, with the user-facing `url` to view it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Album<'a> {
+    pub name: &'a str,
+    pub id: &'a AlbumId,
+    pub url: String,
+}
+
+/// A new asset, with the user-facing `url` to view it, and its thumbnail (if
+/// that could be fetched) at `width`x`height` px. The full `asset` is there
+/// for notifiers' convenience, but isn't part of the payload.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NewAsset<'a> {
+    #[serde(skip)]
+    pub asset: &'a Asset,
+    pub guid: &'a Guid,
+    #[serde(rename = "type")]
+    pub asset_type: AssetType,
+    pub contributor: &'a str,
+    pub caption: Option<&'a str>,
+    pub batch_date_created: Option<DateTime<Utc>>,
+    pub url: String,
+    pub thumbnail_url: Option<&'a Url>,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl<'a> Update<'a> {
+    /// Describe the `new_assets` (with their `thumbnail_urls`) and the
+    /// `removed_assets`, out of all the `album_assets`.
+    pub fn new(
+        config: &'a Config,
+        album_assets: &'a [Asset],
+        new_assets: &[&'a Asset],
+        thumbnail_urls: &'a HashMap<Checksum, Url>,
+        removed_assets: Vec<&'a Guid>,
+    ) -> Self {
+        Update {
+            subject: email::subject(config),
+            thumbnail_urls,
+            album_assets,
+            album: Album {
+                name: &config.album_name,
+                id: &config.album_id,
+                url: config.album_id.url(),
+            },
+            new_assets: new_assets
+                .iter()
+                .map(|asset| NewAsset {
+                    asset,
+                    guid: &asset.guid,
+                    asset_type: asset.asset_type,
+                    contributor: &asset.contributor,
+                    caption: asset.caption.as_deref(),
+                    batch_date_created: asset.batch_date_created,
+                    url: config.album_id.asset_url(&asset.guid),
+                    thumbnail_url: thumbnail_urls.get(&asset.checksum),
+                    width: asset.width,
+                    height: asset.height,
+                })
+                .collect(),
+            removed_assets,
+        }
+    }
+
+    /// Just the new assets themselves.
+    pub fn assets(&self) -> Vec<&'a Asset> {
+        self.new_assets.iter().map(|new| new.asset).collect()
+    }
+}
+
+/// For notifiers that upload each new asset somewhere (identified in the
+/// `state` by `destination`): `upload` anything that failed last time, then
+/// the new assets, remembering any failures to try again next time (unless
+/// they've since disappeared from the album).
+pub fn upload_each(
+    update: &Update<'_>,
+    state: &mut State,
+    destination: &str,
+    upload: impl Fn(&Asset) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut guids = state
+        .pending_uploads
+        .remove(destination)
+        .unwrap_or_default();
+    for new in &update.new_assets {
+        if !guids.contains(new.guid) {
+            guids.push(new.guid.clone());
+        }
+    }
+
+    let album: HashMap<&Guid, &Asset> = update
+        .album_assets
+        .iter()
+        .map(|asset| (&asset.guid, asset))
+        .collect();
+    let mut failed = vec![];
+    let mut errors = vec![];
+    for guid in guids {
+        if let Some(asset) = album.get(&guid) {
+            if let Err(err) = upload(asset) {
+                errors.push(format!("{}: {}", guid, err));
+                failed.push(guid);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        state
+            .pending_uploads
+            .insert(destination.to_string(), failed);
+    }
+    match errors.as_slice() {
+        [] => Ok(()),
+        [error] => Err(format!("{} (will retry next time)", error).into()),
+        errors => Err(format!(
+            "{} uploads failed (will retry next time): {}",
+            errors.len(),
+            errors.join("; ")
+        )
+        .into()),
+    }
+}
+
+/// Tell all the configured notifiers about some new and removed assets, out of
+/// all the `album_assets` (if there are any, or they have something to retry),
+/// returning whether there's anything new to record in the `state`. Failures are warned about (and
+/// each outcome reported), rather than stopping the emails, unless the
+/// notifier says to abort.
+///
+/// Any notifier that can't be set up (eg as its token file is missing) is
+/// reported as failing, and the new assets are remembered for it, to tell it
+/// about once it can be.
+pub fn send(
+    config: &Config,
+    album_assets: &[Asset],
+    new_assets: &[&Asset],
+    removed_assets: Vec<&Guid>,
+    state: &mut State,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let changed = !new_assets.is_empty() || !removed_assets.is_empty();
+    let mut missed_any = false;
+    let mut notifiers = vec![];
+    for notifier_config in &config.notifiers {
+        let description = describe(notifier_config);
+        match notifier(config, notifier_config) {
+            Ok(notifier) => {
+                let missed = state.unnotified.remove(&description).unwrap_or_default();
+                if changed || !missed.is_empty() || notifier.has_retries(state) {
+                    notifiers.push((notifier, missed));
+                }
+            }
+            Err(err) => {
+                let mut error = format!("unable to set up: {}", err);
+                if !new_assets.is_empty() || state.unnotified.contains_key(&description) {
+                    let missed = state.unnotified.entry(description.clone()).or_default();
+                    for asset in new_assets {
+                        if !missed.contains(&asset.guid) {
+                            missed.push(asset.guid.clone());
+                        }
+                    }
+                    missed_any |= !new_assets.is_empty();
+                    error.push_str(" (will retry next time)");
+                }
+                report::notified(description, Err(error));
+            }
+        }
+    }
+    if notifiers.is_empty() {
+        return Ok(missed_any);
+    }
+
+    // Any that abort the run on failure go first, so that the others haven't
+    // already been told about an update that will be tried again.
+    notifiers.sort_by_key(|(notifier, _)| !notifier.aborts_on_failure());
+
+    // Each notifier is told about the new assets, after any it missed before
+    // (that are still in the album).
+    let album: HashMap<&Guid, &Asset> = album_assets
+        .iter()
+        .map(|asset| (&asset.guid, asset))
+        .collect();
+    let notifiers: Vec<_> = notifiers
+        .into_iter()
+        .map(|(notifier, missed)| {
+            let mut assets: Vec<&Asset> = missed
+                .iter()
+                .filter(|guid| !new_assets.iter().any(|asset| asset.guid == **guid))
+                .filter_map(|guid| album.get(guid).copied())
+                .collect();
+            assets.extend(new_assets);
+            (notifier, assets)
+        })
+        .collect();
+
+    // Notifiers can manage without thumbnails if need be.
+    let mut guids: Vec<&Guid> = vec![];
+    for (_, assets) in &notifiers {
+        for asset in assets {
+            if !guids.contains(&&asset.guid) {
+                guids.push(&asset.guid);
+            }
+        }
+    }
+    let thumbnail_urls = if guids.is_empty() {
+        HashMap::new()
+    } else {
+        fetch::thumbnail_urls(&guids, config).unwrap_or_else(|err| {
+            report::warn(format_args!("unable to fetch thumbnail URLs: {}", err));
+            HashMap::new()
+        })
+    };
+
+    for (notifier, assets) in notifiers {
+        let update = Update::new(
+            config,
+            album_assets,
+            &assets,
+            &thumbnail_urls,
+            removed_assets.clone(),
+        );
+        let result = notifier.notify(&update, state);
+        if let Err(err) = &result {
+            if notifier.aborts_on_failure() {
+                return Err(format!("{}: {}", notifier, err).into());
+            }
+        }
+        report::notified(&notifier, result.map_err(|err| err.to_string()));
+    }
+    Ok(true)
+}
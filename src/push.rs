@@ -0,0 +1,177 @@
+//! Push notifications to phones, via ntfy or Gotify
+//!
+//! Either way, the notification's title is the email subject, and its message
+//! the counts of new photos and videos. Tapping it opens the album, and it
+//! shows the first thumbnail (if there is one).
+
+use crate::html;
+use crate::notify::{Notifier, Update};
+use crate::state::State;
+use crate::types::*;
+use crate::utils;
+use serde_json::json;
+use std::fmt;
+
+/// A configured ntfy topic.
+pub struct Ntfy<'a> {
+    config: &'a NtfyConfig,
+    token: Option<String>,
+}
+
+/// A configured Gotify application.
+pub struct Gotify<'a> {
+    config: &'a GotifyConfig,
+    token: String,
+}
+
+/// What goes in a push notification, whichever the service.
+struct Notification<'a> {
+    title: &'a str,
+    message: String,
+    click: &'a str,
+    attach: Option<&'a str>,
+}
+
+impl<'a> Notification<'a> {
+    /// The notification for an update, if it's worth one (there's no point
+    /// pinging people about things disappearing).
+    fn new(update: &'a Update<'_>) -> Option<Self> {
+        if update.new_assets.is_empty() {
+            return None;
+        }
+        Some(Notification {
+            title: &update.subject,
+            message: html::counts(&update.assets()),
+            click: &update.album.url,
+            attach: update
+                .new_assets
+                .iter()
+                .find_map(|new| new.thumbnail_url)
+                .map(|url| url.0.as_str()),
+        })
+    }
+}
+
+impl<'a> Ntfy<'a> {
+    /// Set up an ntfy topic, loading the access token (if any).
+    pub fn new(config: &'a NtfyConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let token = match &config.token_file {
+            Some(fname) => Some(String::from_utf8(utils::load_secret(fname)?)?),
+            None => None,
+        };
+        Ok(Ntfy { config, token })
+    }
+}
+
+impl Notifier for Ntfy<'_> {
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        _state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let notification = match Notification::new(update) {
+            Some(notification) => notification,
+            None => return Ok(()),
+        };
+        // Published as JSON (to the server's root, rather than the topic's
+        // URL), to avoid squeezing non-ASCII titles into headers.
+        let mut body = json!({
+            "topic": self.config.topic,
+            "title": notification.title,
+            "message": notification.message,
+            "click": notification.click,
+        });
+        if let Some(attach) = notification.attach {
+            body["attach"] = json!(attach);
+            body["filename"] = json!("thumbnail.jpg");
+        }
+        let mut request = surf::post(&self.config.server).body_json(&body)?;
+        if let Some(token) = &self.token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        send(request)
+    }
+}
+
+impl<'a> Gotify<'a> {
+    /// Set up a Gotify application, loading its token.
+    pub fn new(config: &'a GotifyConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let token = String::from_utf8(utils::load_secret(&config.token_file)?)?;
+        Ok(Gotify { config, token })
+    }
+}
+
+impl Notifier for Gotify<'_> {
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        _state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let notification = match Notification::new(update) {
+            Some(notification) => notification,
+            None => return Ok(()),
+        };
+        // The click action and image are extras for the Android app.
+        let mut extras = json!({ "click": { "url": notification.click } });
+        if let Some(attach) = notification.attach {
+            extras["bigImageUrl"] = json!(attach);
+        }
+        let mut body = json!({
+            "title": notification.title,
+            "message": notification.message,
+            "extras": { "client::notification": extras },
+        });
+        if let Some(priority) = self.config.priority {
+            body["priority"] = json!(priority);
+        }
+        let url = format!("{}/message", self.config.server.trim_end_matches('/'));
+        send(
+            surf::post(url)
+                .header("X-Gotify-Key", self.token.as_str())
+                .body_json(&body)?,
+        )
+    }
+}
+
+/// Send a request, checking it succeeded.
+fn send(request: surf::RequestBuilder) -> Result<(), Box<dyn std::error::Error>> {
+    let (status, body) = smol::block_on(async {
+        let mut response = request.send().await?;
+        let body = response.body_string().await?;
+        Ok::<_, surf::Error>((response.status(), body))
+    })
+    .map_err(|err| err.to_string())?;
+    if !status.is_success() {
+        return Err(format!("HTTP status {}: {}", status, body.trim()).into());
+    }
+    Ok(())
+}
+
+impl fmt::Display for Ntfy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ntfy topic {}", self.config.topic)
+    }
+}
+
+impl fmt::Display for Gotify<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gotify server {}", self.config.server)
+    }
+}
+
+// Not derived, to keep the tokens out of any debug output.
+impl fmt::Debug for Ntfy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ntfy")
+            .field("config", self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for Gotify<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Gotify")
+            .field("config", self.config)
+            .finish_non_exhaustive()
+    }
+}
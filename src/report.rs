@@ -0,0 +1,242 @@
+//! Run report: a machine-readable summary of a check, for monitoring
+//!
+//! Warnings and fetch timings are collected from wherever they happen (via
+//! `warn` and `timed`), and the rest is filled in by `main` as it goes. With
+//! `--report json`, the report is printed on stdout at the end of the run (or
+//! when giving up on it), instead of the usual message, and the exit code says
+//! how it went. Otherwise the exit code is just 0, or 1 if the run was
+//! abandoned.
+
+use crate::state::Delivery;
+use crate::types::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+/// The report for the current run, if one is wanted (see `start`).
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+/// How to report on a run.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    /// Just say when an email was sent, and print any warnings on stderr.
+    Text,
+    /// Print a JSON summary on stdout (as well as the warnings on stderr).
+    Json,
+}
+
+/// How a run went overall.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    /// Everything went fine, but there was nothing to send.
+    Ok,
+    /// Everything went fine, and something was sent (an email, or an update
+    /// to a notifier).
+    Delivered,
+    /// The run finished, but something didn't work (eg a notifier or email
+    /// failed), so there were warnings.
+    Warnings,
+    /// The run was abandoned, so will be tried again next time.
+    Error,
+}
+
+impl Outcome {
+    /// The process exit code for the outcome, with `--report json`. (Invalid
+    /// command-line options exit with 2.)
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Ok => 0,
+            Outcome::Error => 1,
+            Outcome::Warnings => 3,
+            Outcome::Delivered => 4,
+        }
+    }
+}
+
+/// Everything in the report.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Report {
+    pub outcome: Outcome,
+    pub exit_code: i32,
+    /// Why the run was abandoned, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub album: Album,
+    pub assets: Counts,
+    /// How many assets were emailed (to any recipients).
+    pub emailed: usize,
+    /// The outcome for each notifier that was told anything, and each email
+    /// recipient.
+    pub notifiers: Vec<NotifierResult>,
+    /// The requests to iCloud by type: "webstream" (listing the album),
+    /// "webasseturls" (getting links to the images and videos), and
+    /// "download".
+    pub fetch_timings: BTreeMap<&'static str, Timing>,
+    pub warnings: Vec<String>,
+}
+
+/// Which album the report is about.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+}
+
+/// How many assets there were, and of those, how many are new, or have been
+/// updated (ie edited since last time), or have disappeared since last time.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Counts {
+    pub total: usize,
+    pub new: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+/// The outcome of telling a notifier, or emailing a recipient.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NotifierResult {
+    pub notifier: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How many requests of a type were made, and how long they took in total.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Timing {
+    pub requests: u32,
+    pub seconds: f64,
+}
+
+/// Start collecting a report on the run.
+pub fn start(config: &Config) {
+    *lock() = Some(Report {
+        outcome: Outcome::Ok,
+        exit_code: Outcome::Ok.exit_code(),
+        error: None,
+        album: Album {
+            id: config.album_id.to_string(),
+            name: config.album_name.clone(),
+            url: config.album_id.url(),
+        },
+        assets: Counts::default(),
+        emailed: 0,
+        notifiers: vec![],
+        fetch_timings: BTreeMap::new(),
+        warnings: vec![],
+    });
+}
+
+/// Update the report (if there is one).
+pub fn record(update: impl FnOnce(&mut Report)) {
+    if let Some(report) = lock().as_mut() {
+        update(report);
+    }
+}
+
+/// Warn about something that went wrong (but isn't worth giving up over).
+pub fn warn(message: impl Display) {
+    eprintln!("Warning: {}", message);
+    record(|report| report.warnings.push(message.to_string()));
+}
+
+/// Record how a notifier got on, warning about any failure.
+pub fn notified(notifier: impl Display, result: Result<(), String>) {
+    if let Err(err) = &result {
+        warn(format_args!("unable to notify {}: {}", notifier, err));
+    }
+    record(|report| report.notifiers.push(notifier_result(notifier, result)));
+}
+
+/// Record how emailing a recipient went, warning about any failure.
+pub fn emailed(recipient: &str, delivery: &Delivery) {
+    let result = match delivery {
+        Delivery::Sent => Ok(()),
+        Delivery::Failed(err) => {
+            warn(format_args!("unable to email {}: {}", recipient, err));
+            Err(err.clone())
+        }
+    };
+    let notifier = format!("email to {}", recipient);
+    record(|report| report.notifiers.push(notifier_result(notifier, result)));
+}
+
+/// Time a request to iCloud of some type.
+pub fn timed<T>(request: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    let seconds = start.elapsed().as_secs_f64();
+    record(|report| {
+        let timing = report.fetch_timings.entry(request).or_default();
+        timing.requests += 1;
+        timing.seconds += seconds;
+    });
+    result
+}
+
+/// Finish the run, printing the report (if there is one), and returning the
+/// exit code.
+pub fn finish() -> i32 {
+    match lock().take() {
+        Some(report) => {
+            let outcome = if !report.warnings.is_empty() {
+                Outcome::Warnings
+            } else if report.notifiers.iter().any(|notifier| notifier.ok) {
+                Outcome::Delivered
+            } else {
+                Outcome::Ok
+            };
+            print(report, outcome, None);
+            outcome.exit_code()
+        }
+        None => 0,
+    }
+}
+
+/// Give up on the run, printing the report (if there is one) with the reason,
+/// and returning the exit code.
+pub fn fail(error: String) -> i32 {
+    if let Some(report) = lock().take() {
+        print(report, Outcome::Error, Some(error));
+    }
+    Outcome::Error.exit_code()
+}
+
+/// Print the report, with the outcome filled in.
+fn print(mut report: Report, outcome: Outcome, error: Option<String>) {
+    report.outcome = outcome;
+    report.exit_code = outcome.exit_code();
+    report.error = error;
+    for timing in report.fetch_timings.values_mut() {
+        timing.seconds = (timing.seconds * 1000.0).round() / 1000.0;
+    }
+    match serde_json::to_string_pretty(&report) {
+        Ok(json) => println!("{}", json),
+        Err(err) => eprintln!("Unable to write report: {}", err),
+    }
+}
+
+/// The report, whatever any other thread was doing with it.
+fn lock() -> MutexGuard<'static, Option<Report>> {
+    REPORT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A notifier's outcome, as reported.
+fn notifier_result(notifier: impl Display, result: Result<(), String>) -> NotifierResult {
+    NotifierResult {
+        notifier: notifier.to_string(),
+        ok: result.is_ok(),
+        error: result.err(),
+    }
+}
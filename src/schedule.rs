@@ -0,0 +1,433 @@
+//! Decide which recipients are due an email, and with what
+//!
+//! Newly seen assets are queued as pending in the state. Each recipient is owed
+//! every pending asset first seen since they were last emailed, and gets them
+//! either straight away, or (if they have a digest schedule) once the next
+//! scheduled time has passed. Nobody is emailed during their quiet hours, and
+//! everyone waits while uploads are still arriving, if a settle period is
+//! configured.
+
+use crate::state::State;
+use crate::types::*;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+
+/// A set of recipients, in the same time zone, who are all due the same
+/// assets.
+#[derive(Debug)]
+pub struct Batch<'a> {
+    pub recipients: Vec<&'a Recipient>,
+    pub guids: Vec<Guid>,
+    pub time_zone: Tz,
+}
+
+/// All the recipients who should be emailed now, grouped by the assets they're
+/// owed and their time zone (so that recipients on the same schedule get a
+/// single email).
+pub fn due<'a>(config: &'a Config, state: &State, now: DateTime<Utc>) -> Vec<Batch<'a>> {
+    let mut batches: Vec<Batch<'a>> = vec![];
+    for recipient in active(config, state) {
+        let time_zone = time_zone(config, recipient);
+        if !is_due(config, recipient, time_zone, state, now) {
+            continue;
+        }
+        let guids = owed(recipient, state);
+        if guids.is_empty() {
+            continue;
+        }
+        match batches
+            .iter_mut()
+            .find(|b| b.guids == guids && b.time_zone == time_zone)
+        {
+            Some(batch) => batch.recipients.push(recipient),
+            None => batches.push(Batch {
+                recipients: vec![recipient],
+                guids,
+                time_zone,
+            }),
+        }
+    }
+    batches
+}
+
+/// Whether uploads have settled down enough to send emails: ie the most
+/// recent pending asset was uploaded (or if that's unknown, first seen by us)
+/// at least the configured settle period ago.
+pub fn settled(
+    config: &Config,
+    state: &State,
+    assets_by_guid: &HashMap<&Guid, &Asset>,
+    now: DateTime<Utc>,
+) -> bool {
+    let settle = match config.settle_minutes {
+        Some(minutes) => Duration::minutes(minutes.into()),
+        None => return true,
+    };
+    let newest = state
+        .pending
+        .iter()
+        .filter_map(|pending| {
+            let asset = assets_by_guid.get(&pending.guid)?;
+            Some(asset.batch_date_created.unwrap_or(pending.first_seen))
+        })
+        .max();
+    newest.is_none_or(|newest| now - newest >= settle)
+}
+
+/// Drop any pending assets that every (subscribed) recipient has now been
+/// sent.
+pub fn prune(config: &Config, state: &mut State) {
+    let last_sent: Vec<_> = active(config, state)
+        .into_iter()
+        .map(|r| state.last_sent.get(&r.addr).copied())
+        .collect();
+    state.pending.retain(|pending| {
+        last_sent
+            .iter()
+            .any(|last| last.is_none_or(|last| pending.first_seen > last))
+    });
+}
+
+/// The recipients who haven't unsubscribed.
+fn active<'a>(config: &'a Config, state: &State) -> Vec<&'a Recipient> {
+    config
+        .recipient_email_addrs
+        .iter()
+        .filter(|r| !state.suppressed.contains(&r.addr.to_lowercase()))
+        .collect()
+}
+
+/// The pending assets that a recipient hasn't been sent yet.
+fn owed(recipient: &Recipient, state: &State) -> Vec<Guid> {
+    let last_sent = state.last_sent.get(&recipient.addr);
+    state
+        .pending
+        .iter()
+        .filter(|pending| last_sent.is_none_or(|last| pending.first_seen > *last))
+        .map(|pending| pending.guid.clone())
+        .collect()
+}
+
+/// The time zone for a recipient: their own, or the default one.
+fn time_zone(config: &Config, recipient: &Recipient) -> Tz {
+    recipient
+        .time_zone
+        .unwrap_or_else(|| default_time_zone(config))
+}
+
+/// The configured time zone, or the system one (or failing both, UTC).
+pub fn default_time_zone(config: &Config) -> Tz {
+    config
+        .time_zone
+        .or_else(|| iana_time_zone::get_timezone().ok()?.parse().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+/// Whether a recipient should be emailed now (if there's anything to send):
+/// not if it's their quiet hours, and for digest recipients, only if a
+/// scheduled time has passed since they were last emailed (or first owed
+/// anything).
+fn is_due(
+    config: &Config,
+    recipient: &Recipient,
+    time_zone: Tz,
+    state: &State,
+    now: DateTime<Utc>,
+) -> bool {
+    let quiet_hours = recipient
+        .quiet_hours
+        .as_ref()
+        .or(config.quiet_hours.as_ref());
+    if let Some(quiet_hours) = quiet_hours {
+        if is_quiet(quiet_hours, now.with_timezone(&time_zone).time()) {
+            return false;
+        }
+    }
+    let digest = match &recipient.digest {
+        Some(digest) => digest,
+        None => return true,
+    };
+
+    // Someone who's never been emailed waits for a scheduled time after they
+    // were first owed anything (the oldest pending asset).
+    let since = state
+        .last_sent
+        .get(&recipient.addr)
+        .copied()
+        .or_else(|| state.pending.first().map(|pending| pending.first_seen));
+    since.is_some_and(|since| since < last_scheduled(digest, time_zone, now))
+}
+
+/// Whether a (local) time is within quiet hours, which may span midnight.
+fn is_quiet(quiet_hours: &QuietHours, time: NaiveTime) -> bool {
+    let QuietHours { start, end } = *quiet_hours;
+    if start <= end {
+        start <= time && time < end
+    } else {
+        start <= time || time < end
+    }
+}
+
+/// The most recent scheduled digest time, at or before `now`.
+fn last_scheduled(digest: &Digest, time_zone: Tz, now: DateTime<Utc>) -> DateTime<Utc> {
+    let mut date = now.with_timezone(&time_zone).date_naive();
+    loop {
+        if digest.day.is_none_or(|day| date.weekday() == day) {
+            // (A time skipped by a daylight-saving change just doesn't happen.)
+            let scheduled = time_zone.from_local_datetime(&date.and_time(digest.time));
+            if let Some(scheduled) = scheduled.earliest().map(|t| t.with_timezone(&Utc)) {
+                if scheduled <= now {
+                    return scheduled;
+                }
+            }
+        }
+        date = date.pred_opt().expect("date in range");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Pending;
+    use crate::testing;
+    use serde_json::json;
+
+    /// A time, in UTC.
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().expect("valid time")
+    }
+
+    /// An asset pending since some time.
+    fn pending(guid: &str, first_seen: &str) -> Pending {
+        Pending {
+            guid: testing::id(guid),
+            first_seen: at(first_seen),
+        }
+    }
+
+    /// A config with a single recipient (in the default time zone,
+    /// Europe/London).
+    fn config_for(recipient: serde_json::Value) -> Config {
+        testing::config(json!({ "recipient-email-addrs": [recipient] }))
+    }
+
+    /// Whether the config's first recipient is due an email.
+    fn first_is_due(config: &Config, state: &State, now: &str) -> bool {
+        let recipient = &config.recipient_email_addrs[0];
+        is_due(
+            config,
+            recipient,
+            time_zone(config, recipient),
+            state,
+            at(now),
+        )
+    }
+
+    #[test]
+    fn weekly_digest() {
+        // Sundays at 18:00 BST (17:00 UTC); 5 May 2024 was a Sunday.
+        let config = config_for(json!({
+            "addr": "mum@example.com",
+            "digest": {"day": "sunday", "time": "18:00"},
+        }));
+        let mut state = State::default();
+        state
+            .last_sent
+            .insert("mum@example.com".to_string(), at("2024-04-28T17:00:00Z"));
+        state.pending.push(pending("G1", "2024-05-01T09:00:00Z"));
+        assert!(!first_is_due(&config, &state, "2024-05-05T16:59:59Z"));
+        assert!(first_is_due(&config, &state, "2024-05-05T17:00:00Z"));
+        assert!(first_is_due(&config, &state, "2024-05-06T09:00:00Z"));
+        assert_eq!(
+            owed(&config.recipient_email_addrs[0], &state),
+            [testing::id("G1")]
+        );
+
+        // Once it's sent, there's nothing more until the next Sunday.
+        state
+            .last_sent
+            .insert("mum@example.com".to_string(), at("2024-05-05T17:00:00Z"));
+        assert!(!first_is_due(&config, &state, "2024-05-12T16:59:59Z"));
+        assert!(first_is_due(&config, &state, "2024-05-12T17:00:00Z"));
+    }
+
+    #[test]
+    fn first_digest() {
+        // Never emailed, and first owed something just after Sunday's digest.
+        let config = config_for(json!({
+            "addr": "mum@example.com",
+            "digest": {"day": "sunday", "time": "18:00"},
+        }));
+        let mut state = State::default();
+        state.pending.push(pending("G1", "2024-05-05T17:30:00Z"));
+        assert!(!first_is_due(&config, &state, "2024-05-05T17:45:00Z"));
+        assert!(!first_is_due(&config, &state, "2024-05-12T16:59:59Z"));
+        assert!(first_is_due(&config, &state, "2024-05-12T17:00:00Z"));
+
+        // (With nothing pending, there's nothing to wait for.)
+        assert!(!first_is_due(
+            &config,
+            &State::default(),
+            "2024-05-12T17:00:00Z"
+        ));
+    }
+
+    #[test]
+    fn skipped_by_daylight_saving() {
+        // 01:30 didn't happen in London on 31 March 2024, when the clocks went
+        // forward at 01:00, so the day before's is the latest that day.
+        let london: Tz = "Europe/London".parse().unwrap();
+        let digest = Digest {
+            day: None,
+            time: NaiveTime::from_hms_opt(1, 30, 0).unwrap(),
+        };
+        assert_eq!(
+            last_scheduled(&digest, london, at("2024-03-31T23:00:00Z")),
+            at("2024-03-30T01:30:00Z")
+        );
+        assert_eq!(
+            last_scheduled(&digest, london, at("2024-04-01T00:30:00Z")),
+            at("2024-04-01T00:30:00Z")
+        );
+    }
+
+    #[test]
+    fn pruning() {
+        let config = testing::config(json!({
+            "recipient-email-addrs": [
+                "mum@example.com",
+                "dad@example.com",
+                "Gran@Example.com",
+            ],
+        }));
+        let mut state = State::default();
+        state.suppressed.insert("gran@example.com".to_string());
+        state.pending.push(pending("G1", "2024-05-04T12:00:00Z"));
+        state.pending.push(pending("G2", "2024-05-04T13:00:00Z"));
+        state
+            .last_sent
+            .insert("mum@example.com".to_string(), at("2024-05-04T12:30:00Z"));
+
+        // Dad hasn't been sent either yet.
+        prune(&config, &mut state);
+        assert_eq!(state.pending.len(), 2);
+
+        // Now he's been sent the first (and Gran has unsubscribed, so doesn't
+        // count), but Mum hasn't been sent the second.
+        state
+            .last_sent
+            .insert("dad@example.com".to_string(), at("2024-05-04T14:00:00Z"));
+        prune(&config, &mut state);
+        let guids: Vec<&Guid> = state.pending.iter().map(|p| &p.guid).collect();
+        assert_eq!(guids, [&testing::id::<Guid>("G2")]);
+
+        state
+            .last_sent
+            .insert("mum@example.com".to_string(), at("2024-05-04T14:00:00Z"));
+        prune(&config, &mut state);
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let overnight = QuietHours {
+            start: time("22:00"),
+            end: time("07:00"),
+        };
+        for quiet in &["22:00", "23:59", "00:00", "03:00", "06:59"] {
+            assert!(is_quiet(&overnight, time(quiet)), "{} is quiet", quiet);
+        }
+        for not_quiet in &["07:00", "12:00", "21:59"] {
+            assert!(
+                !is_quiet(&overnight, time(not_quiet)),
+                "{} isn't",
+                not_quiet
+            );
+        }
+
+        let lunchtime = QuietHours {
+            start: time("12:00"),
+            end: time("13:00"),
+        };
+        assert!(is_quiet(&lunchtime, time("12:30")));
+        assert!(!is_quiet(&lunchtime, time("13:00")));
+        assert!(!is_quiet(&lunchtime, time("23:00")));
+    }
+
+    #[test]
+    fn own_time_zone() {
+        // Quiet from 22:00 to 07:00 wherever they are: 22:30 UTC is 23:30 in
+        // London, but 18:30 in New York.
+        let config = testing::config(json!({
+            "recipient-email-addrs": [
+                "mum@example.com",
+                {"addr": "dad@example.com", "time-zone": "America/New_York"},
+            ],
+            "quiet-hours": {"start": "22:00", "end": "07:00"},
+        }));
+        let mum = &config.recipient_email_addrs[0];
+        let dad = &config.recipient_email_addrs[1];
+        assert_eq!(time_zone(&config, mum), chrono_tz::Europe::London);
+        assert_eq!(time_zone(&config, dad), chrono_tz::America::New_York);
+
+        let mut state = State::default();
+        state.pending.push(pending("G1", "2024-05-04T22:00:00Z"));
+        let now = at("2024-05-04T22:30:00Z");
+        assert!(!is_due(&config, mum, time_zone(&config, mum), &state, now));
+        assert!(is_due(&config, dad, time_zone(&config, dad), &state, now));
+
+        // So only Dad is emailed (in his own time zone).
+        let batches = due(&config, &state, now);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].recipients[0].addr, "dad@example.com");
+        assert_eq!(batches[0].time_zone, chrono_tz::America::New_York);
+
+        // And a digest is at their own local time: 18:00 in New York is 22:00
+        // UTC.
+        let config = config_for(json!({
+            "addr": "dad@example.com",
+            "time-zone": "America/New_York",
+            "digest": {"time": "18:00"},
+        }));
+        state.pending = vec![pending("G1", "2024-05-04T12:00:00Z")];
+        assert!(!first_is_due(&config, &state, "2024-05-04T21:59:00Z"));
+        assert!(first_is_due(&config, &state, "2024-05-04T22:00:00Z"));
+    }
+
+    #[test]
+    fn settling() {
+        let config = testing::config(json!({ "settle-minutes": 30 }));
+        // Uploaded at 12:00, but only spotted at 12:20.
+        let mut photo = testing::photo("G1");
+        let mut state = State::default();
+        state.pending.push(pending("G1", "2024-05-04T12:20:00Z"));
+        let settled_at = |photo: &Asset, now: &str| {
+            let assets_by_guid: HashMap<&Guid, &Asset> =
+                std::iter::once((&photo.guid, photo)).collect();
+            settled(&config, &state, &assets_by_guid, at(now))
+        };
+        assert!(!settled_at(&photo, "2024-05-04T12:29:59Z"));
+        assert!(settled_at(&photo, "2024-05-04T12:30:00Z"));
+
+        // Without an upload time, it's when it was spotted that counts.
+        photo.batch_date_created = None;
+        assert!(!settled_at(&photo, "2024-05-04T12:49:59Z"));
+        assert!(settled_at(&photo, "2024-05-04T12:50:00Z"));
+
+        // Anything that's since disappeared doesn't hold things up.
+        let other = testing::photo("G2");
+        assert!(settled_at(&other, "2024-05-04T12:21:00Z"));
+
+        // And without a settle period, there's no waiting.
+        let config = testing::config(json!({}));
+        let assets_by_guid = std::iter::once((&photo.guid, &photo)).collect();
+        assert!(settled(
+            &config,
+            &state,
+            &assets_by_guid,
+            at("2024-05-04T12:20:00Z")
+        ));
+    }
+}
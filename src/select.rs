@@ -0,0 +1,130 @@
+//! Choose which new assets get thumbnails in the email
+
+use crate::types::*;
+use std::collections::HashMap;
+
+/// Choose at most `config.max_thumbnails` of the new assets to show, according
+/// to the configured strategy. The chosen assets stay in album order.
+pub fn thumbnails<'a>(config: &Config, assets: &[&'a Asset]) -> Vec<&'a Asset> {
+    let max = match config.max_thumbnails {
+        Some(max) if max < assets.len() => max,
+        _ => return assets.to_vec(),
+    };
+    match config.thumbnail_selection {
+        Selection::First => assets[..max].to_vec(),
+        Selection::Even => (0..max).map(|i| assets[i * assets.len() / max]).collect(),
+        Selection::FairShare => fair_share(assets, max),
+    }
+}
+
+/// Give each contributor an equal number of slots, in turn, until either the
+/// slots run out or a contributor has no more assets (in which case the
+/// others get their share). Each contributor's earliest assets are shown.
+fn fair_share<'a>(assets: &[&'a Asset], max: usize) -> Vec<&'a Asset> {
+    // How many assets each contributor has, in order of first appearance.
+    let mut available: Vec<(&str, usize)> = vec![];
+    for asset in assets {
+        match available.iter_mut().find(|(c, _)| *c == asset.contributor) {
+            Some((_, count)) => *count += 1,
+            None => available.push((&asset.contributor, 1)),
+        }
+    }
+
+    // Hand out slots round-robin.
+    let mut quota: HashMap<&str, usize> = HashMap::new();
+    let mut remaining = max;
+    while remaining > 0 {
+        for (contributor, count) in available.iter_mut().filter(|(_, n)| *n > 0) {
+            if remaining == 0 {
+                break;
+            }
+            *quota.entry(contributor).or_default() += 1;
+            *count -= 1;
+            remaining -= 1;
+        }
+    }
+
+    // Take each contributor's quota from the front.
+    assets
+        .iter()
+        .filter(|asset| match quota.get_mut(asset.contributor.as_str()) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                true
+            }
+            _ => false,
+        })
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    /// The Guids of the assets chosen from some (Guid, contributor) pairs.
+    fn chosen(selection: &str, max: usize, assets: &[(&str, &str)]) -> Vec<String> {
+        let config = testing::config(json!({
+            "max-thumbnails": max,
+            "thumbnail-selection": selection,
+        }));
+        let assets: Vec<Asset> = assets
+            .iter()
+            .map(|(guid, contributor)| Asset {
+                contributor: contributor.to_string(),
+                ..testing::photo(guid)
+            })
+            .collect();
+        let assets: Vec<&Asset> = assets.iter().collect();
+        thumbnails(&config, &assets)
+            .iter()
+            .map(|asset| asset.guid.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn even() {
+        let assets = [
+            ("G1", "Alice"),
+            ("G2", "Alice"),
+            ("G3", "Alice"),
+            ("G4", "Alice"),
+            ("G5", "Alice"),
+            ("G6", "Alice"),
+        ];
+        assert_eq!(chosen("even", 3, &assets), ["G1", "G3", "G5"]);
+        assert_eq!(chosen("even", 1, &assets), ["G1"]);
+        assert!(chosen("even", 0, &assets).is_empty());
+        assert_eq!(chosen("even", 5, &assets), ["G1", "G2", "G3", "G4", "G5"]);
+        assert_eq!(chosen("even", 6, &assets).len(), 6);
+        assert_eq!(chosen("even", 7, &assets).len(), 6);
+    }
+
+    #[test]
+    fn fair_share() {
+        // Bob runs out after one, then Carol after two.
+        let assets = [
+            ("A1", "Alice"),
+            ("A2", "Alice"),
+            ("B1", "Bob"),
+            ("A3", "Alice"),
+            ("C1", "Carol"),
+            ("A4", "Alice"),
+            ("C2", "Carol"),
+        ];
+        assert_eq!(chosen("fair-share", 3, &assets), ["A1", "B1", "C1"]);
+        assert_eq!(
+            chosen("fair-share", 5, &assets),
+            ["A1", "A2", "B1", "C1", "C2"]
+        );
+        assert_eq!(
+            chosen("fair-share", 6, &assets),
+            ["A1", "A2", "B1", "A3", "C1", "C2"]
+        );
+        assert_eq!(chosen("fair-share", 1, &assets), ["A1"]);
+        assert!(chosen("fair-share", 0, &assets).is_empty());
+        assert_eq!(chosen("fair-share", 8, &assets).len(), 7);
+    }
+}
@@ -0,0 +1,208 @@
+//! Compose a contact sheet: a single JPEG showing all the thumbnails in a grid
+//!
+//! It's attached to the email, so shows up even where remote images are
+//! blocked (and one attachment looks a lot less like spam than dozens of
+//! remote images).
+
+use crate::html;
+use crate::types::*;
+use image::imageops::{self, FilterType};
+use image::{Rgb, RgbImage};
+
+/// The Content-ID the HTML refers to the contact sheet by.
+pub static CONTENT_ID: &str = "contact-sheet@icloud-biff";
+
+/// Space around each thumbnail.
+const PADDING: u32 = 8;
+
+/// Caption text is drawn with an 8x8 px font, scaled up by this much.
+const FONT_SCALE: u32 = 2;
+
+/// Caption lines under each thumbnail (the caption is truncated to fit).
+const CAPTION_LINES: u32 = 2;
+
+const BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+const PLACEHOLDER: Rgb<u8> = Rgb([221, 221, 221]);
+const TEXT: Rgb<u8> = Rgb([51, 51, 51]);
+
+/// A rendered contact sheet.
+#[derive(Debug)]
+pub struct ContactSheet {
+    pub jpeg: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Compose the thumbnail `images` (the raw downloaded data, or `None` if that
+/// failed, in which case a placeholder is shown) for some `assets` into a
+/// contact sheet.
+pub fn build(
+    config: &Config,
+    sheet_config: &ContactSheetConfig,
+    assets: &[&Asset],
+    images: Vec<Option<Vec<u8>>>,
+) -> Result<ContactSheet, Box<dyn std::error::Error>> {
+    let tile = u32::from(config.thumbnail_size);
+    let caption_height = if sheet_config.captions {
+        CAPTION_LINES * line_height() + PADDING / 2
+    } else {
+        0
+    };
+    let columns = sheet_config.columns.clamp(1, assets.len().max(1) as u32);
+    let rows = (assets.len() as u32).div_ceil(columns).max(1);
+    let width = columns * (tile + PADDING) + PADDING;
+    let height = rows * (tile + caption_height + PADDING) + PADDING;
+    let mut canvas = RgbImage::from_pixel(width, height, BACKGROUND);
+
+    for (n, (asset, image)) in assets.iter().zip(images).enumerate() {
+        let x = PADDING + (n as u32 % columns) * (tile + PADDING);
+        let y = PADDING + (n as u32 / columns) * (tile + caption_height + PADDING);
+
+        // The thumbnail, scaled to fit (and centred in) its tile.
+        let thumbnail = image
+            .and_then(|data| image::load_from_memory(&data).ok())
+            .map(|image| image.resize(tile, tile, FilterType::Triangle).to_rgb8());
+        match thumbnail {
+            Some(thumbnail) => {
+                let left = x + (tile - thumbnail.width()) / 2;
+                let top = y + (tile - thumbnail.height()) / 2;
+                imageops::replace(&mut canvas, &thumbnail, left.into(), top.into());
+            }
+            None => fill(&mut canvas, x, y, tile, tile, PLACEHOLDER),
+        }
+        if asset.asset_type == AssetType::Video {
+            play_badge(&mut canvas, x + tile / 2, y + tile / 2);
+        }
+
+        if let Some(caption) = html::caption(asset).filter(|_| sheet_config.captions) {
+            let max_chars = (tile / (8 * FONT_SCALE)) as usize;
+            for (line, text) in wrap(&caption, max_chars).iter().enumerate() {
+                let text_width = text.chars().count() as u32 * 8 * FONT_SCALE;
+                let left = x + tile.saturating_sub(text_width) / 2;
+                let top = y + tile + PADDING / 2 + line as u32 * line_height();
+                draw_text(&mut canvas, left, top, text);
+            }
+        }
+    }
+
+    let mut jpeg = vec![];
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 85)
+        .encode_image(&canvas)?;
+    Ok(ContactSheet {
+        jpeg,
+        width,
+        height,
+    })
+}
+
+/// The height of a line of caption text, including spacing.
+fn line_height() -> u32 {
+    8 * FONT_SCALE + 2
+}
+
+/// Fill a rectangle with a solid colour.
+fn fill(canvas: &mut RgbImage, x: u32, y: u32, width: u32, height: u32, colour: Rgb<u8>) {
+    for py in y..y + height {
+        for px in x..x + width {
+            canvas.put_pixel(px, py, colour);
+        }
+    }
+}
+
+/// Draw a "play" badge (a white triangle on a translucent dark circle),
+/// centred on a point.
+fn play_badge(canvas: &mut RgbImage, cx: u32, cy: u32) {
+    let radius = 20i32;
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            if dx * dx + dy * dy > radius * radius {
+                continue;
+            }
+            let (px, py) = ((cx as i32 + dx) as u32, (cy as i32 + dy) as u32);
+            if px >= canvas.width() || py >= canvas.height() {
+                continue;
+            }
+            // A triangle pointing right, a bit right of centre to look
+            // balanced.
+            let in_triangle = (-6..=10).contains(&dx) && dy.abs() * 16 <= (10 - dx) * 10;
+            let pixel = canvas.get_pixel_mut(px, py);
+            *pixel = if in_triangle {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb(pixel.0.map(|c| (u16::from(c) * 2 / 5) as u8))
+            };
+        }
+    }
+}
+
+/// Split text into at most `CAPTION_LINES` lines of `max_chars`, breaking at
+/// spaces where possible, with an ellipsis if it doesn't all fit.
+fn wrap(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let len = line.chars().count();
+        if len > 0 && len + 1 + word.chars().count() > max_chars {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    lines.push(line);
+
+    // Hard-break any overlong lines, then truncate.
+    let mut lines: Vec<String> = lines
+        .iter()
+        .flat_map(|line| {
+            let chars: Vec<char> = line.chars().collect();
+            chars
+                .chunks(max_chars.max(1))
+                .map(|chunk| chunk.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    if lines.len() > CAPTION_LINES as usize {
+        lines.truncate(CAPTION_LINES as usize);
+        let last = lines.last_mut().expect("at least one line");
+        let mut chars: Vec<char> = last.chars().collect();
+        chars.truncate(max_chars.saturating_sub(1));
+        *last = chars.into_iter().collect::<String>() + "\u{2026}";
+    }
+    lines
+}
+
+/// Draw a line of text, with its top left corner at a point. Characters that
+/// the bitmap font doesn't cover are drawn as "?".
+fn draw_text(canvas: &mut RgbImage, x: u32, y: u32, text: &str) {
+    use font8x8::UnicodeFonts;
+    for (n, c) in text.chars().enumerate() {
+        let glyph = font8x8::BASIC_FONTS
+            .get(c)
+            .or_else(|| font8x8::LATIN_FONTS.get(c))
+            .or_else(|| match c {
+                '\u{2026}' => Some([0, 0, 0, 0, 0, 0, 0b0100_1001, 0]),
+                '\u{2018}' | '\u{2019}' => font8x8::BASIC_FONTS.get('\''),
+                '\u{201c}' | '\u{201d}' => font8x8::BASIC_FONTS.get('"'),
+                _ => None,
+            })
+            .or_else(|| font8x8::BASIC_FONTS.get('?'))
+            .expect("font has '?'");
+        let left = x + n as u32 * 8 * FONT_SCALE;
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..8 {
+                // Bit 0 is the leftmost pixel.
+                if bits & (1 << col) != 0 {
+                    let px = left + col * FONT_SCALE;
+                    let py = y + row as u32 * FONT_SCALE;
+                    if px + FONT_SCALE <= canvas.width()
+                        && py + FONT_SCALE <= canvas.height()
+                    {
+                        fill(canvas, px, py, FONT_SCALE, FONT_SCALE, TEXT);
+                    }
+                }
+            }
+        }
+    }
+}
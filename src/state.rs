@@ -0,0 +1,153 @@
+//! Persistent state between runs
+
+use crate::types::*;
+use crate::utils;
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::{fs, io};
+
+//////////////////////////////////////////////////////////////////////////////
+///
+/// # Everything remembered between runs
+///
+/// This is stored as JSON in the configured `db_file`. Older versions of the
+/// program stored just a list of the seen Guids, so that format is still
+/// accepted on load (but the new format is always written on save).
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct State {
+    /// All the Guids in the album as of the last run, in album order.
+    pub seen_guids: Vec<Guid>,
+    /// The checksum of the best version of each of them, to tell when one has
+    /// been edited.
+    #[serde(default)]
+    pub seen_checksums: BTreeMap<Guid, Checksum>,
+    /// Email threading details.
+    #[serde(default)]
+    pub thread: ThreadState,
+    /// Outcome of the most recent attempt to email each recipient.
+    #[serde(default)]
+    pub deliveries: Deliveries,
+    /// Lowercased addresses of recipients who have unsubscribed.
+    #[serde(default)]
+    pub suppressed: BTreeSet<String>,
+    /// Assets not yet emailed to every recipient, in the order first seen.
+    #[serde(default)]
+    pub pending: Vec<Pending>,
+    /// When each recipient was last emailed.
+    #[serde(default)]
+    pub last_sent: BTreeMap<String, DateTime<Utc>>,
+    /// The entries in each Atom feed (by file name), newest first.
+    #[serde(default)]
+    pub feeds: BTreeMap<String, Vec<FeedEntry>>,
+    /// The new assets of each failed run of each command (by its command
+    /// line), to be run again (with their details filled in afresh, as the
+    /// thumbnail URLs don't last).
+    #[serde(default)]
+    pub exec_pending: BTreeMap<String, Vec<Vec<Guid>>>,
+    /// The assets still to be uploaded to each destination (by URL), after
+    /// failing before.
+    #[serde(default)]
+    pub pending_uploads: BTreeMap<String, Vec<Guid>>,
+    /// The new assets that each notifier (by description) missed, as it
+    /// couldn't be set up, to tell it about next time.
+    #[serde(default)]
+    pub unnotified: BTreeMap<String, Vec<Guid>>,
+}
+
+/// An asset that is waiting to be emailed to some recipients.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Pending {
+    pub guid: Guid,
+    pub first_seen: DateTime<Utc>,
+}
+
+/// An Atom feed entry, for one update. The `content` is HTML.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FeedEntry {
+    pub id: String,
+    pub title: String,
+    pub updated: DateTime<Utc>,
+    pub authors: Vec<String>,
+    pub content: String,
+}
+
+/// Email threading state: the Message-ID of the first email sent for this
+/// album (which all later emails refer back to), and how many emails have
+/// been sent in total. Recipients who get their own copy each have their own
+/// first Message-ID (by address), as that's the one in their mailbox.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ThreadState {
+    pub root_message_id: Option<String>,
+    #[serde(default)]
+    pub root_message_ids: BTreeMap<String, String>,
+    pub num_sent: u64,
+}
+
+/// Delivery outcome per recipient email address.
+pub type Deliveries = BTreeMap<String, Delivery>;
+
+/// Outcome of trying to email a single recipient.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Delivery {
+    Sent,
+    Failed(String),
+}
+
+/// An exclusive (advisory) lock on the state file, held until dropped. Runs
+/// and the unsubscribe server each hold this from loading the state until
+/// they've saved it, so that neither overwrites the other's changes (and an
+/// overlapping run waits for the last one to finish).
+#[derive(Debug)]
+pub struct Lock {
+    /// (Only kept open to hold the lock.)
+    _file: fs::File,
+}
+
+/// Wait for the lock on the state file. It's taken on a separate `.lock` file,
+/// as saving replaces the state file itself.
+pub fn lock(fname: &str) -> Result<Lock, Box<dyn std::error::Error>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(format!("{}.lock", fname))?;
+    file.lock_exclusive()?;
+    Ok(Lock { _file: file })
+}
+
+/// Load the state file, in either the current or legacy format. A missing
+/// file is just treated as empty state (ie the first run), but one that can't
+/// be read or parsed is an error, rather than forgetting everything.
+pub fn load(fname: &str, _lock: &Lock) -> Result<State, Box<dyn std::error::Error>> {
+    let raw_json = match fs::read_to_string(fname) {
+        Ok(raw_json) => raw_json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+        Err(err) => return Err(err.into()),
+    };
+    serde_json::from_str(&raw_json)
+        .or_else(|err| {
+            serde_json::from_str::<Vec<Guid>>(&raw_json)
+                .map(|seen_guids| State {
+                    seen_guids,
+                    ..Default::default()
+                })
+                .map_err(|_| err)
+        })
+        .map_err(|err| err.into())
+}
+
+/// Save the state file (replacing it in one go, so it's never left half
+/// written).
+pub fn save(
+    state: &State,
+    fname: &str,
+    _lock: &Lock,
+) -> Result<(), Box<dyn std::error::Error>> {
+    utils::save_json(state, fname)
+}
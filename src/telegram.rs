@@ -0,0 +1,239 @@
+//! Telegram notifier: send updates to a chat via the Bot API
+//!
+//! The thumbnails are sent as albums (`sendMediaGroup`) of up to 10, with a
+//! caption linking to the shared album on the first. Telegram fetches the
+//! thumbnails from iCloud itself, so there's nothing to download or upload.
+
+use crate::html;
+use crate::notify::{Notifier, Update};
+use crate::state::State;
+use crate::types::*;
+use crate::utils;
+use serde_json::json;
+use std::fmt;
+
+/// The most items Telegram allows in one media group (it also needs at least
+/// 2).
+const MAX_MEDIA_GROUP: usize = 10;
+
+/// A configured Telegram chat.
+pub struct Telegram<'a> {
+    config: &'a TelegramConfig,
+    bot_token: String,
+}
+
+impl<'a> Telegram<'a> {
+    /// Set up a Telegram chat, loading the bot token.
+    pub fn new(config: &'a TelegramConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let bot_token = String::from_utf8(utils::load_secret(&config.bot_token_file)?)?;
+        Ok(Telegram { config, bot_token })
+    }
+
+    /// Call a Bot API method.
+    fn call(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let url = format!(
+            "{}/bot{}/{}",
+            self.config.api_base.trim_end_matches('/'),
+            self.bot_token,
+            method
+        );
+        let request = surf::post(url).body_json(&params)?;
+        // (The URL contains the token, so errors are described by the method
+        // instead.)
+        let (status, body) = smol::block_on(async {
+            let mut response = request.send().await?;
+            let body = response.body_string().await?;
+            Ok::<_, surf::Error>((response.status(), body))
+        })
+        .map_err(|err| format!("{} request failed: {}", method, err))?;
+
+        // Responses look like {"ok": false, "description": "Bad Request: ..."}
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+        if json["ok"].as_bool() != Some(true) {
+            let description = json["description"].as_str().unwrap_or(&body);
+            return Err(format!("{} failed ({}): {}", method, status, description).into());
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for Telegram<'_> {
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        _state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (method, params) in messages(&self.config.chat_id, update) {
+            self.call(method, params)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Telegram<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Telegram chat {}", self.config.chat_id)
+    }
+}
+
+// Not derived, to keep the bot token out of any debug output.
+impl fmt::Debug for Telegram<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Telegram")
+            .field("config", self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The Bot API calls (methods and their parameters) that send an update to a
+/// chat: the thumbnails in albums of up to 10, or just a message if there
+/// aren't any.
+fn messages(
+    chat_id: &str,
+    update: &Update<'_>,
+) -> Vec<(&'static str, serde_json::Value)> {
+    // There's no point pinging people about things disappearing.
+    if update.new_assets.is_empty() {
+        return vec![];
+    }
+
+    let album = &update.album;
+    let summary = format!(
+        "New in <a href=\"{}\">{}</a>: {}",
+        utils::escape_html(&album.url),
+        utils::escape_html(album.name),
+        html::counts(&update.assets())
+    );
+    let with_thumbnails: Vec<_> = update
+        .new_assets
+        .iter()
+        .filter(|new| new.thumbnail_url.is_some())
+        .collect();
+    if with_thumbnails.is_empty() {
+        return vec![(
+            "sendMessage",
+            json!({
+                "chat_id": chat_id,
+                "text": summary,
+                "parse_mode": "HTML",
+            }),
+        )];
+    }
+
+    let mut messages = vec![];
+    for (chunk_num, chunk) in with_thumbnails.chunks(MAX_MEDIA_GROUP).enumerate() {
+        // Each photo's caption is shown when it's opened (with the summary at
+        // the top of the first, which is shown as the album caption).
+        let media: Vec<serde_json::Value> = chunk
+            .iter()
+            .enumerate()
+            .map(|(n, new)| {
+                let caption = html::caption(new.asset).map(|c| utils::escape_html(&c));
+                let caption = match (chunk_num == 0 && n == 0, caption) {
+                    (true, Some(caption)) => format!("{}\n\n{}", summary, caption),
+                    (true, None) => summary.clone(),
+                    (false, caption) => caption.unwrap_or_default(),
+                };
+                json!({
+                    "type": "photo",
+                    "media": new.thumbnail_url.map(|url| &url.0),
+                    "caption": caption,
+                    "parse_mode": "HTML",
+                })
+            })
+            .collect();
+
+        // A group of one isn't allowed, so that's just sent as a photo.
+        messages.push(match media.as_slice() {
+            [single] => (
+                "sendPhoto",
+                json!({
+                    "chat_id": chat_id,
+                    "photo": single["media"],
+                    "caption": single["caption"],
+                    "parse_mode": "HTML",
+                }),
+            ),
+            _ => (
+                "sendMediaGroup",
+                json!({ "chat_id": chat_id, "media": media }),
+            ),
+        });
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::collections::HashMap;
+
+    /// An update with `count` new photos (the first with a caption).
+    fn messages_for(count: usize) -> Vec<(&'static str, serde_json::Value)> {
+        let config = testing::config(json!({}));
+        let mut assets: Vec<Asset> = (1..=count)
+            .map(|n| testing::photo(&format!("G{}", n)))
+            .collect();
+        assets[0].caption = Some("Rex & Fido".to_string());
+        let new_assets: Vec<&Asset> = assets.iter().collect();
+        let thumbnail_urls = testing::thumbnail_urls(&new_assets);
+        let update = Update::new(&config, &assets, &new_assets, &thumbnail_urls, vec![]);
+        messages("@dogs", &update)
+    }
+
+    #[test]
+    fn one_photo() {
+        let messages = messages_for(1);
+        assert_eq!(messages.len(), 1);
+        let (method, params) = &messages[0];
+        assert_eq!(*method, "sendPhoto");
+        assert_eq!(params["chat_id"], "@dogs");
+        assert_eq!(params["photo"], "https://example.com/G1-thumb.jpg");
+        assert_eq!(
+            params["caption"],
+            "New in <a href=\"https://www.icloud.com/sharedalbum/#B0zAxqIORGhwx3u\">\
+             Dogs</a>: 1 photo\n\nRex &amp; Fido"
+        );
+    }
+
+    #[test]
+    fn ten_photos() {
+        let messages = messages_for(10);
+        assert_eq!(messages.len(), 1);
+        let (method, params) = &messages[0];
+        assert_eq!(*method, "sendMediaGroup");
+        let media = params["media"].as_array().unwrap();
+        assert_eq!(media.len(), 10);
+        assert!(media[0]["caption"]
+            .as_str()
+            .unwrap()
+            .starts_with("New in <a href="));
+        assert_eq!(media[9]["media"], "https://example.com/G10-thumb.jpg");
+        assert_eq!(media[9]["caption"], "");
+    }
+
+    #[test]
+    fn eleven_photos() {
+        // The last one's on its own, so it can't be a group.
+        let messages = messages_for(11);
+        let methods: Vec<&str> = messages.iter().map(|(method, _)| *method).collect();
+        assert_eq!(methods, ["sendMediaGroup", "sendPhoto"]);
+        assert_eq!(messages[1].1["photo"], "https://example.com/G11-thumb.jpg");
+        assert_eq!(messages[1].1["caption"], "");
+    }
+
+    #[test]
+    fn removed_only() {
+        let config = testing::config(json!({}));
+        let assets = [testing::photo("G1")];
+        let removed = testing::id("G2");
+        let thumbnail_urls = HashMap::new();
+        let update = Update::new(&config, &assets, &[], &thumbnail_urls, vec![&removed]);
+        assert!(messages("@dogs", &update).is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+//! Fixtures for unit tests
+
+use crate::types::*;
+use chrono::{TimeZone, Utc};
+use serde_json::json;
+
+/// A config for the album "Dogs", with some `extra` settings (as in the JSON
+/// config file) on top of the bare minimum.
+pub fn config(extra: serde_json::Value) -> Config {
+    let mut config = json!({
+        "album-name": "Dogs",
+        "album-id": "B0zAxqIORGhwx3u",
+        "recipient-email-addrs": ["mum@example.com"],
+        "sender-email-addr": "bot@example.com",
+        "sender-email-name": "Photo Bot",
+        "db-file": "/nonexistent/db.json",
+        "time-zone": "Europe/London",
+    });
+    if let (Some(config), Some(extra)) = (config.as_object_mut(), extra.as_object()) {
+        config.extend(extra.clone());
+    }
+    serde_json::from_value(config).expect("valid config")
+}
+
+/// A Guid (or checksum) from a string.
+pub fn id<T: serde::de::DeserializeOwned>(id: &str) -> T {
+    serde_json::from_value(json!(id)).expect("valid ID")
+}
+
+/// A 240x180 photo, posted by Alice at noon on Saturday 4 May 2024 (UTC),
+/// in batch "B1". Its checksums are its Guid with "-thumb", "-2x" and "-best"
+/// appended.
+pub fn photo(guid: &str) -> Asset {
+    Asset {
+        guid: id(guid),
+        asset_type: AssetType::Photo,
+        contributor: "Alice".to_string(),
+        caption: None,
+        batch_guid: Some("B1".to_string()),
+        batch_date_created: Some(Utc.with_ymd_and_hms(2024, 5, 4, 12, 0, 0).unwrap()),
+        batch_comment: None,
+        checksum: id(&format!("{}-thumb", guid)),
+        checksum_2x: Some(id(&format!("{}-2x", guid))),
+        checksum_best: id(&format!("{}-best", guid)),
+        width: 240,
+        height: 180,
+    }
+}
+
+/// The same, but a video.
+pub fn video(guid: &str) -> Asset {
+    Asset {
+        asset_type: AssetType::Video,
+        ..photo(guid)
+    }
+}
+
+/// Thumbnail URLs for some assets (at 1x and 2x), at
+/// "https://example.com/<checksum>.jpg".
+pub fn thumbnail_urls(assets: &[&Asset]) -> std::collections::HashMap<Checksum, Url> {
+    assets
+        .iter()
+        .flat_map(|asset| std::iter::once(&asset.checksum).chain(&asset.checksum_2x))
+        .map(|checksum| {
+            let url = Url(format!("https://example.com/{}.jpg", checksum));
+            (checksum.clone(), url)
+        })
+        .collect()
+}
@@ -1,7 +1,10 @@
 //! Domain types for iCloud-biff
+use chrono::{DateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
 use derivative::Derivative;
 use derive_more::Display;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::convert::TryFrom;
 
 //////////////////////////////////////////////////////////////////////////////
 ///
@@ -9,37 +12,514 @@ use serde::{Deserialize, Serialize};
 ///
 /// The `album_name` is title for humans (eg "My lovely dogs"). The `album_id`
 /// is the identifier for iCloud, eg "B0zAxqIORGhwx3u".
+///
+/// Each of the `recipient_email_addrs` is either just an address, or an
+/// address with options: see `Recipient`.
+///
+/// With `threading` enabled (the default), every email carries threading
+/// headers that refer back to the first email sent for the album, so mail
+/// clients group them all into one conversation.
+///
+/// The `delivery` mode determines whether recipients can see each other's
+/// addresses: see `DeliveryMode`.
+///
+/// Times of day are interpreted in the `time_zone` (an IANA name such as
+/// "Europe/London"), defaulting to the system time zone. No emails are sent
+/// during `quiet_hours`, if specified. Both of these can be overridden per
+/// recipient.
+///
+/// If `settle_minutes` is specified, then no emails are sent until that long
+/// after the most recent upload, so that a flurry of uploads from a phone ends
+/// up in one email rather than several.
+///
+/// At most `max_thumbnails` thumbnails are shown per email (if specified), as
+/// chosen by the `thumbnail_selection` strategy. Each is shown at
+/// `thumbnail_size` px on its long edge (or smaller, if iCloud doesn't have a
+/// big enough version). If `contact_sheet` is configured, they're shown in a
+/// single attached image instead.
+///
+/// Emails are DKIM-signed if `dkim` is configured.
+///
+/// Emails to recipients with a key are encrypted using the `gpg_path` or
+/// `openssl_path` executable, as appropriate.
+///
+/// If `unsubscribe` is configured, each email gets one-click unsubscribe
+/// headers (only possible with `DeliveryMode::Individual`, or for encrypted
+/// recipients, since the link is specific to the recipient).
+///
+/// As well as email, any other `notifiers` are told about each update as soon
+/// as it's spotted: see `NotifierConfig`.
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
     pub album_name: String,
     pub album_id: AlbumId,
-    pub recipient_email_addrs: Vec<String>,
+    pub recipient_email_addrs: Vec<Recipient>,
     pub sender_email_addr: String,
     pub sender_email_name: String,
     pub db_file: String,
     #[serde(default = "default_sendmail_path")]
     pub sendmail_path: String,
+    #[serde(default = "default_gpg_path")]
+    pub gpg_path: String,
+    #[serde(default = "default_openssl_path")]
+    pub openssl_path: String,
+    #[serde(default = "default_true")]
+    pub threading: bool,
+    #[serde(default)]
+    pub delivery: DeliveryMode,
+    #[serde(default)]
+    pub settle_minutes: Option<u32>,
+    #[serde(default)]
+    pub time_zone: Option<Tz>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    #[serde(default)]
+    pub unsubscribe: Option<UnsubscribeConfig>,
+    #[serde(default)]
+    pub dkim: Option<DkimConfig>,
+    #[serde(default)]
+    pub max_thumbnails: Option<usize>,
+    #[serde(default)]
+    pub thumbnail_selection: Selection,
+    #[serde(default = "default_thumbnail_size")]
+    pub thumbnail_size: u16,
+    #[serde(default)]
+    pub contact_sheet: Option<ContactSheetConfig>,
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+}
+
+/// A recipient's email address (which should just be the pure address, eg
+/// "mum@example.com"), and optionally:
+///
+///  - a `digest` schedule: if specified, new assets are batched up and sent on
+///    that schedule, rather than as soon as they're spotted
+///  - their `time_zone` (otherwise the `Config` one applies)
+///  - their `quiet_hours` (otherwise the `Config` ones apply)
+///  - a `pgp_key_file` or `smime_cert_file`: if specified, they get their own
+///    copy of each email, encrypted to that key
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RecipientEntry")]
+pub struct Recipient {
+    pub addr: String,
+    pub digest: Option<Digest>,
+    pub time_zone: Option<Tz>,
+    pub quiet_hours: Option<QuietHours>,
+    pub encryption: Option<Encryption>,
+}
+
+/// Config representation of a `Recipient`: a plain address is also accepted,
+/// for backwards compatibility.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RecipientEntry {
+    Addr(String),
+    #[serde(rename_all = "kebab-case")]
+    Full {
+        addr: String,
+        #[serde(default)]
+        digest: Option<Digest>,
+        #[serde(default)]
+        time_zone: Option<Tz>,
+        #[serde(default)]
+        quiet_hours: Option<QuietHours>,
+        #[serde(default)]
+        pgp_key_file: Option<String>,
+        #[serde(default)]
+        smime_cert_file: Option<String>,
+    },
+}
+
+impl TryFrom<RecipientEntry> for Recipient {
+    type Error = String;
+
+    fn try_from(entry: RecipientEntry) -> Result<Self, Self::Error> {
+        match entry {
+            RecipientEntry::Addr(addr) => Ok(Recipient {
+                addr,
+                digest: None,
+                time_zone: None,
+                quiet_hours: None,
+                encryption: None,
+            }),
+            RecipientEntry::Full {
+                addr,
+                digest,
+                time_zone,
+                quiet_hours,
+                pgp_key_file,
+                smime_cert_file,
+            } => {
+                let encryption = match (pgp_key_file, smime_cert_file) {
+                    (None, None) => None,
+                    (Some(key), None) => Some(Encryption::Pgp(key)),
+                    (None, Some(cert)) => Some(Encryption::Smime(cert)),
+                    (Some(_), Some(_)) => {
+                        return Err(format!(
+                            "{} has both a PGP key and an S/MIME certificate",
+                            addr
+                        ))
+                    }
+                };
+                Ok(Recipient {
+                    addr,
+                    digest,
+                    time_zone,
+                    quiet_hours,
+                    encryption,
+                })
+            }
+        }
+    }
+}
+
+/// How to encrypt emails to a recipient.
+#[derive(Debug)]
+pub enum Encryption {
+    /// PGP/MIME (RFC 3156), to the public key in this (armored or binary)
+    /// file.
+    Pgp(String),
+    /// S/MIME (RFC 8551), to the PEM certificate in this file.
+    Smime(String),
+}
+
+/// A digest schedule: daily at `time` (eg "18:00", in the recipient's time
+/// zone), or weekly if a `day` is also specified (eg "sunday").
+#[derive(Debug, Deserialize)]
+pub struct Digest {
+    #[serde(default)]
+    pub day: Option<Weekday>,
+    #[serde(deserialize_with = "hours_minutes")]
+    pub time: NaiveTime,
+}
+
+/// A daily window (eg "22:00" to "07:00", in the recipient's time zone)
+/// during which no emails are sent. Anything new is held back until the end.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct QuietHours {
+    #[serde(deserialize_with = "hours_minutes")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "hours_minutes")]
+    pub end: NaiveTime,
+}
+
+/// Deserialize a time of day written as "HH:MM".
+fn hours_minutes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<NaiveTime, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+/// How to address the email to multiple recipients.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Derivative)]
+#[serde(rename_all = "kebab-case")]
+#[derivative(Default)]
+pub enum DeliveryMode {
+    /// One email, with all the recipients listed in `To:` (and a Bcc to the
+    /// sender).
+    #[derivative(Default)]
+    Shared,
+    /// One email, addressed `To:` the sender, with all the recipients in Bcc.
+    Bcc,
+    /// A separate email to each recipient, so one bad address doesn't affect
+    /// the others.
+    Individual,
 }
 
 fn default_sendmail_path() -> String {
     "/usr/sbin/sendmail".to_string()
 }
 
+fn default_gpg_path() -> String {
+    "gpg".to_string()
+}
+
+fn default_openssl_path() -> String {
+    "openssl".to_string()
+}
+
+fn default_thumbnail_size() -> u16 {
+    200
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// How to choose which assets to show, when there are too many new ones.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Derivative)]
+#[serde(rename_all = "kebab-case")]
+#[derivative(Default)]
+pub enum Selection {
+    /// The first ones, in album order.
+    #[derivative(Default)]
+    First,
+    /// A sample spread evenly over the album order.
+    Even,
+    /// An equal share for each contributor (as far as possible).
+    FairShare,
+}
+
+/// Settings for one-click unsubscribe links. The `base_url` is the public URL
+/// (eg "https://example.com/photos") under which the `serve-unsubscribe`
+/// server, listening on `listen_addr`, is reachable. The `secret_file`
+/// contains the key used to sign the links.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UnsubscribeConfig {
+    pub base_url: String,
+    pub secret_file: String,
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: String,
+}
+
+fn default_listen_addr() -> String {
+    "127.0.0.1:8025".to_string()
+}
+
+/// Settings for DKIM signing: the signing `domain` (usually that of the sender
+/// address), the `selector` under which the public key is published in DNS
+/// (at `<selector>._domainkey.<domain>`), and the PEM file containing the RSA
+/// or Ed25519 private key.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DkimConfig {
+    pub domain: String,
+    pub selector: String,
+    pub private_key_file: String,
+}
+
+/// Settings for a contact sheet: the thumbnails are downloaded and composed
+/// into one JPEG (in a grid with `columns` of them, and their `captions` if
+/// enabled), which is attached to the email.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ContactSheetConfig {
+    #[serde(default = "default_columns")]
+    pub columns: u32,
+    #[serde(default)]
+    pub captions: bool,
+}
+
+fn default_columns() -> u32 {
+    4
+}
+
+/// A non-email notifier, identified by its `type` in the config.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum NotifierConfig {
+    Webhook(WebhookConfig),
+    Matrix(MatrixConfig),
+    Telegram(TelegramConfig),
+    Ntfy(NtfyConfig),
+    Gotify(GotifyConfig),
+    Atom(AtomConfig),
+    Exec(ExecConfig),
+    Webdav(WebdavConfig),
+    S3(S3Config),
+}
+
+/// Settings for a webhook: each update is POSTed as JSON to the `url`, signed
+/// with the key in the `secret_file` (if specified), and retried up to
+/// `retries` times if the server can't be reached or has an error.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub secret_file: Option<String>,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// Settings for posting to a Matrix room: the `homeserver` base URL (eg
+/// "https://matrix.example.com"), a file containing the `access_token` of the
+/// account to post as (which must have joined the room), and the `room_id` (eg
+/// "!abcdefg:example.com"). Up to `max_thumbnails` thumbnails are uploaded
+/// and posted as images.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MatrixConfig {
+    pub homeserver: String,
+    pub access_token_file: String,
+    pub room_id: String,
+    #[serde(default = "default_matrix_thumbnails")]
+    pub max_thumbnails: usize,
+}
+
+fn default_matrix_thumbnails() -> usize {
+    4
+}
+
+/// Settings for sending to a Telegram chat: a file containing the `bot_token`
+/// (from @BotFather), and the `chat_id` (eg "-1001234567890", or "@channel").
+/// The `api_base` only needs changing for a self-hosted Bot API server (or a
+/// stand-in for testing).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TelegramConfig {
+    pub bot_token_file: String,
+    pub chat_id: String,
+    #[serde(default = "default_telegram_api_base")]
+    pub api_base: String,
+}
+
+fn default_telegram_api_base() -> String {
+    "https://api.telegram.org".to_string()
+}
+
+/// Settings for ntfy push notifications: the `topic` to publish to, on the
+/// `server` (by default the public ntfy.sh), with an access token from the
+/// `token_file` if the topic needs one.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NtfyConfig {
+    #[serde(default = "default_ntfy_server")]
+    pub server: String,
+    pub topic: String,
+    #[serde(default)]
+    pub token_file: Option<String>,
+}
+
+fn default_ntfy_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+/// Settings for Gotify push notifications: the `server` URL (eg
+/// "https://gotify.example.com"), and a file containing the application
+/// token to send messages with (at the `priority`, if specified).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GotifyConfig {
+    pub server: String,
+    pub token_file: String,
+    #[serde(default)]
+    pub priority: Option<u8>,
+}
+
+/// Settings for an Atom feed, written to the `file`, with the most recent
+/// `max_entries` updates.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AtomConfig {
+    pub file: String,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_max_entries() -> usize {
+    20
+}
+
+/// Settings for running a command (eg a script) about new assets: the
+/// `command` (the program and its arguments, run directly rather than via a
+/// shell) is run once `per` new asset or per run, and killed if it takes longer
+/// than `timeout_seconds`. What happens if it fails is up to `on_failure`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExecConfig {
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub per: ExecPer,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+}
+
+fn default_timeout_seconds() -> u64 {
+    60
+}
+
+/// How often to run a command.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Derivative)]
+#[serde(rename_all = "kebab-case")]
+#[derivative(Default)]
+pub enum ExecPer {
+    /// Once for each new asset.
+    #[derivative(Default)]
+    Asset,
+    /// Once for all the new assets in a run.
+    Run,
+}
+
+/// What to do when a command fails (or times out).
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Derivative)]
+#[serde(rename_all = "kebab-case")]
+#[derivative(Default)]
+pub enum OnFailure {
+    /// Just warn about it.
+    #[derivative(Default)]
+    Ignore,
+    /// Warn about it, and run it again with the same details next time.
+    Retry,
+    /// Stop without recording anything, so that the whole run (emails and
+    /// all) is tried again next time.
+    Abort,
+}
+
+/// Settings for uploading new assets to a WebDAV folder (eg on Nextcloud): the
+/// folder's `url`, and if it needs logging in to, the `username` and a file
+/// containing the password.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebdavConfig {
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password_file: Option<String>,
+}
+
+/// Settings for backing up new assets to an S3-compatible bucket (eg on
+/// MinIO or Backblaze B2): the service's `endpoint` (eg
+/// "https://s3.us-west-004.backblazeb2.com") and `region`, the `bucket`, the
+/// `access_key_id`, and a file containing the secret access key. Each asset's
+/// objects are named by the `layout` (see the README).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3Config {
+    pub endpoint: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub bucket: String,
+    pub access_key_id: String,
+    pub secret_access_key_file: String,
+    #[serde(default = "default_layout")]
+    pub layout: String,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_layout() -> String {
+    "{year}/{month}/{date}-{guid}".to_string()
+}
+
 //////////////////////////////////////////////////////////////////////////////
 //
 // Basic newtypes
 
 /// A Guid identifying a particuar asset.
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[derive(
+    Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Display,
+)]
 pub struct Guid(String);
 
 /// A checksum identifying an asset at a particular resolution.
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize, Display)]
 pub struct Checksum(String);
 
 /// A URL. Insides are public for easy rendering into HTML.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Url(pub String);
 
 /// An album id
@@ -80,18 +560,30 @@ impl AlbumId {
 ///
 /// The `guid` identifies the asset, which can have multiple instantiations at
 /// different resolutions. The `checksum` specifically identfies the best
-/// instantiation of that asset for a thumbnail, with recommended dimensions
-/// `width`x`height` px.
+/// instantiation of that asset for a thumbnail, to be shown at `width`x`height`
+/// px, and `checksum_2x` a sharper one for high-DPI screens (if there is one).
+/// The `checksum_best` is the best quality version there is (for downloading).
+/// The `contributor` is the full name of whoever posted it (with a `caption`,
+/// if they wrote one), as part of an upload batch (identified by `batch_guid`,
+/// if known) created at `batch_date_created` (if known), possibly with a
+/// `batch_comment`.
 #[derive(Debug)]
 pub struct Asset {
     pub guid: Guid,
     pub asset_type: AssetType,
+    pub contributor: String,
+    pub caption: Option<String>,
+    pub batch_guid: Option<String>,
+    pub batch_date_created: Option<DateTime<Utc>>,
+    pub batch_comment: Option<String>,
     pub checksum: Checksum,
+    pub checksum_2x: Option<Checksum>,
+    pub checksum_best: Checksum,
     pub width: u16,
     pub height: u16,
 }
 /// Asset type: photo or video.
-#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Derivative)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Derivative)]
 #[serde(rename_all = "camelCase")]
 #[derivative(Default)]
 pub enum AssetType {
@@ -0,0 +1,295 @@
+//! One-click unsubscribe (RFC 8058)
+//!
+//! Each recipient's email carries a `List-Unsubscribe` link containing their
+//! address, plus an HMAC token proving that we issued the link. The small HTTP
+//! server here checks the token, and adds the address to the suppression list
+//! in the state file, which later runs honour.
+
+use crate::state;
+use crate::types::*;
+use crate::utils;
+use hmac::{Hmac, Mac};
+use horrorshow::helper::doctype;
+use horrorshow::html;
+use sha2::Sha256;
+use std::io::Cursor;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Convenience type for errors.
+type AnyError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Load the secret key used to sign unsubscribe links.
+pub fn load_secret(config: &UnsubscribeConfig) -> Result<Vec<u8>, std::io::Error> {
+    utils::load_secret(&config.secret_file)
+}
+
+/// The unsubscribe link for a particular recipient.
+pub fn link(config: &UnsubscribeConfig, secret: &[u8], addr: &str) -> String {
+    let token = hex::encode(mac(secret, addr).finalize().into_bytes());
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("addr", addr)
+        .append_pair("token", &token)
+        .finish();
+    format!(
+        "{}/unsubscribe?{}",
+        config.base_url.trim_end_matches('/'),
+        query
+    )
+}
+
+/// The HMAC of an address (which is case-insensitive, in practice).
+fn mac(secret: &[u8], addr: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes any key size");
+    mac.update(addr.to_lowercase().as_bytes());
+    mac
+}
+
+/// Check that a hex token is genuine for the address (in constant time).
+fn verify(secret: &[u8], addr: &str, token: &str) -> bool {
+    hex::decode(token)
+        .map(|token| mac(secret, addr).verify_slice(&token).is_ok())
+        .unwrap_or(false)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+///
+/// Run the unsubscribe HTTP server (forever).
+///
+/// A `GET` of the link just shows a confirmation button, since mail scanners
+/// routinely follow links. The button, or a mail client implementing RFC 8058,
+/// does a `POST` to the same link to actually unsubscribe.
+pub fn serve(config: &Config) -> Result<(), AnyError> {
+    let unsubscribe = config
+        .unsubscribe
+        .as_ref()
+        .ok_or("the config has no unsubscribe settings")?;
+    let secret = load_secret(unsubscribe)?;
+    let server = Server::http(&unsubscribe.listen_addr)?;
+    println!(
+        "Serving unsubscribe requests on {}",
+        unsubscribe.listen_addr
+    );
+
+    for request in server.incoming_requests() {
+        let response = handle(config, &secret, &request);
+        if let Err(err) = request.respond(response) {
+            eprintln!("Warning: unable to respond to request: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Handle a single request.
+fn handle(
+    config: &Config,
+    secret: &[u8],
+    request: &Request,
+) -> Response<Cursor<Vec<u8>>> {
+    // Parse the address and token out of the URL. The path is only checked by
+    // suffix, in case a reverse proxy passes through some prefix.
+    let url =
+        match url::Url::parse("http://localhost").and_then(|u| u.join(request.url())) {
+            Ok(url) if url.path().ends_with("/unsubscribe") => url,
+            _ => return page(404, "Not found", "There is nothing here."),
+        };
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+            .unwrap_or_default()
+    };
+    let (addr, token) = (param("addr"), param("token"));
+    if addr.is_empty() || !verify(secret, &addr, &token) {
+        return page(
+            403,
+            "Invalid link",
+            "Sorry, this unsubscribe link is not valid.",
+        );
+    }
+
+    match request.method() {
+        Method::Get => confirmation_page(config, &addr),
+        Method::Post => match suppress(config, &addr) {
+            Ok(()) => page(
+                200,
+                "Unsubscribed",
+                &format!(
+                    "{} will no longer receive updates about the {} album.",
+                    addr, config.album_name
+                ),
+            ),
+            Err(err) => {
+                eprintln!("Warning: unable to unsubscribe {}: {}", addr, err);
+                page(
+                    500,
+                    "Error",
+                    "Sorry, something went wrong. Please try again later.",
+                )
+            }
+        },
+        _ => page(405, "Not allowed", "That request method is not supported."),
+    }
+}
+
+/// Add an address to the suppression list in the state file (waiting for any
+/// run in progress to finish with it first).
+fn suppress(config: &Config, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let lock = state::lock(&config.db_file)?;
+    let mut state = state::load(&config.db_file, &lock)?;
+    state.suppressed.insert(addr.to_lowercase());
+    state::save(&state, &config.db_file, &lock)
+}
+
+/// A page with a button to confirm unsubscribing, that POSTs back to the same
+/// URL (hence no form `action`).
+fn confirmation_page(config: &Config, addr: &str) -> Response<Cursor<Vec<u8>>> {
+    let body = format!(
+        "{}",
+        html! {
+            : doctype::HTML;
+            html {
+                head { title : "Unsubscribe"; }
+                body {
+                    p : format!("Stop emailing {} about the {} album?", addr, config.album_name);
+                    form(method = "post") {
+                        input(type = "hidden", name = "List-Unsubscribe", value = "One-Click");
+                        input(type = "submit", value = "Unsubscribe");
+                    }
+                }
+            }
+        }
+    );
+    html_response(200, body)
+}
+
+/// A simple page with a title and a message.
+fn page(status: u16, title: &str, message: &str) -> Response<Cursor<Vec<u8>>> {
+    let body = format!(
+        "{}",
+        html! {
+            : doctype::HTML;
+            html {
+                head { title : title; }
+                body { p : message; }
+            }
+        }
+    );
+    html_response(status, body)
+}
+
+/// Wrap up an HTML response.
+fn html_response(status: u16, body: String) -> Response<Cursor<Vec<u8>>> {
+    let content_type: Header = "Content-Type: text/html; charset=utf-8".parse().unwrap();
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+    use tiny_http::TestRequest;
+
+    const SECRET: &[u8] = b"secret";
+
+    /// A config with unsubscribe links, and its state file in `dir`.
+    fn config(dir: &tempfile::TempDir) -> Config {
+        testing::config(json!({
+            "db-file": dir.path().join("db.json"),
+            "unsubscribe": {
+                "base-url": "https://example.com/photos/",
+                "secret-file": "/nonexistent/secret",
+            },
+        }))
+    }
+
+    /// The token in an address's link.
+    fn token(config: &Config, addr: &str) -> String {
+        let link = link(config.unsubscribe.as_ref().unwrap(), SECRET, addr);
+        let url = url::Url::parse(&link).unwrap();
+        let (_, token) = url.query_pairs().find(|(k, _)| k == "token").unwrap();
+        token.into_owned()
+    }
+
+    /// Handle a request for a path (under the base URL), returning the status.
+    fn status(config: &Config, method: Method, path: &str) -> u16 {
+        let request: Request = TestRequest::new()
+            .with_method(method)
+            .with_path(path)
+            .into();
+        handle(config, SECRET, &request).status_code().0
+    }
+
+    #[test]
+    fn tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let link = link(
+            config.unsubscribe.as_ref().unwrap(),
+            SECRET,
+            "Mum@Example.com",
+        );
+        assert!(link.starts_with(
+            "https://example.com/photos/unsubscribe?addr=Mum%40Example.com&token="
+        ));
+
+        let token = token(&config, "Mum@Example.com");
+        assert!(verify(SECRET, "Mum@Example.com", &token));
+        assert!(verify(SECRET, "mum@example.com", &token));
+        assert!(!verify(SECRET, "dad@example.com", &token));
+        assert!(!verify(b"another secret", "Mum@Example.com", &token));
+        let mut tampered = token.clone();
+        let last = if tampered.pop() == Some('0') {
+            '1'
+        } else {
+            '0'
+        };
+        tampered.push(last);
+        assert!(!verify(SECRET, "Mum@Example.com", &tampered));
+        assert!(!verify(SECRET, "Mum@Example.com", "not hex"));
+        assert!(!verify(SECRET, "Mum@Example.com", ""));
+    }
+
+    #[test]
+    fn requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let suppressed = || {
+            let lock = state::lock(&config.db_file).unwrap();
+            state::load(&config.db_file, &lock).unwrap().suppressed
+        };
+        let path = |addr: &str, token: &str| {
+            let query = url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("addr", addr)
+                .append_pair("token", token)
+                .finish();
+            format!("/photos/unsubscribe?{}", query)
+        };
+
+        // Bad links are turned away.
+        let mum = path("Mum@Example.com", &token(&config, "Mum@Example.com"));
+        let forged = path("dad@example.com", &token(&config, "Mum@Example.com"));
+        assert_eq!(status(&config, Method::Post, &forged), 403);
+        assert_eq!(status(&config, Method::Post, "/photos/unsubscribe"), 403);
+        assert_eq!(status(&config, Method::Post, "/photos/elsewhere"), 404);
+
+        // Just following the link only asks for confirmation.
+        assert_eq!(status(&config, Method::Get, &mum), 200);
+        assert!(suppressed().is_empty());
+
+        // Posting to it unsubscribes, whatever the case of the address.
+        assert_eq!(status(&config, Method::Post, &mum), 200);
+        let upper = path("MUM@EXAMPLE.COM", &token(&config, "MUM@EXAMPLE.COM"));
+        assert_eq!(status(&config, Method::Post, &upper), 200);
+        let mixed = path("mUm@example.COM", &token(&config, "mum@example.com"));
+        assert_eq!(status(&config, Method::Post, &mixed), 200);
+        assert_eq!(
+            suppressed().into_iter().collect::<Vec<_>>(),
+            ["mum@example.com"]
+        );
+    }
+}
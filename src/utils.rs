@@ -38,8 +38,9 @@ where
 {
     fn or_die(self, msg: S) -> T {
         self.unwrap_or_else(|err| {
-            eprintln!("Unable to {}: {}", msg.into(), err);
-            std::process::exit(1);
+            let message = format!("Unable to {}: {}", msg.into(), err);
+            eprintln!("{}", message);
+            std::process::exit(crate::report::fail(message));
         })
     }
 }
@@ -57,11 +58,37 @@ where
     serde_json::from_str(&raw_json).map_err(|e| e.into())
 }
 
-/// Save a JSON file.
+/// Save a JSON file, via a temporary file that then replaces it, so that the
+/// file is never left half written.
 pub fn save_json<T>(this: &T, fname: &str) -> Result<(), Box<dyn std::error::Error>>
 where
     T: Serialize,
 {
     let raw_json = serde_json::to_vec_pretty(this)?;
-    fs::write(fname, raw_json).map_err(|e| e.into())
+    let tmp_file = format!("{}.tmp", fname);
+    fs::write(&tmp_file, raw_json)?;
+    fs::rename(&tmp_file, fname).map_err(|e| e.into())
+}
+
+/// Load a secret key from a file (ignoring any surrounding whitespace, such as
+/// a trailing newline).
+pub fn load_secret(fname: &str) -> Result<Vec<u8>, std::io::Error> {
+    Ok(fs::read_to_string(fname)?.trim().as_bytes().to_vec())
+}
+
+/// An identifier from iCloud (such as a Guid), made safe to use in a file
+/// name.
+pub fn safe_file_name(id: &str) -> String {
+    id.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect()
+}
+
+/// Escape text for including in HTML (including attribute values), for the
+/// odd places where it's not being rendered via `horrorshow`.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
@@ -0,0 +1,142 @@
+//! WebDAV output: upload new assets to a folder (eg for a photo frame)
+//!
+//! Each new asset's best quality version is downloaded from iCloud and `PUT`
+//! in the folder, named by the date it was added and its GUID (so uploading it
+//! again just overwrites it). Any that fail are remembered in the state, and
+//! tried again next time.
+
+use crate::fetch;
+use crate::notify::{self, Notifier, Update};
+use crate::schedule;
+use crate::state::State;
+use crate::types::*;
+use crate::utils;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::fmt;
+use surf::http::Mime;
+
+/// A configured WebDAV folder.
+pub struct Webdav<'a> {
+    config: &'a Config,
+    webdav: &'a WebdavConfig,
+    password: Option<String>,
+}
+
+impl<'a> Webdav<'a> {
+    /// Set up a WebDAV folder, loading the password (if any).
+    pub fn new(
+        config: &'a Config,
+        webdav: &'a WebdavConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let password = match &webdav.password_file {
+            Some(fname) => Some(String::from_utf8(utils::load_secret(fname)?)?),
+            None => None,
+        };
+        Ok(Webdav {
+            config,
+            webdav,
+            password,
+        })
+    }
+
+    /// Download an asset and upload it to the folder.
+    fn upload(&self, asset: &Asset) -> Result<(), Box<dyn std::error::Error>> {
+        let data = fetch::best(asset, self.config).map_err(|err| err.to_string())?;
+        let (extension, mimetype) = fetch::file_type(&data, asset.asset_type);
+        let name = file_name(self.config, asset, extension);
+
+        let url = format!("{}/{}", self.webdav.url.trim_end_matches('/'), name);
+        let mimetype: Mime = mimetype.parse()?;
+        let mut request = surf::put(url).content_type(mimetype).body(data);
+        if let Some(username) = &self.webdav.username {
+            let password = self.password.as_deref().unwrap_or_default();
+            let credentials = BASE64.encode(format!("{}:{}", username, password));
+            request = request.header("Authorization", format!("Basic {}", credentials));
+        }
+        let (status, body) = smol::block_on(async {
+            let mut response = request.send().await?;
+            let body = response.body_string().await?;
+            Ok::<_, surf::Error>((response.status(), body))
+        })
+        .map_err(|err| err.to_string())?;
+        if !status.is_success() {
+            return Err(format!("HTTP status {}: {}", status, body.trim()).into());
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for Webdav<'_> {
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        notify::upload_each(update, state, &self.webdav.url, |asset| self.upload(asset))
+    }
+
+    fn has_retries(&self, state: &State) -> bool {
+        state.pending_uploads.contains_key(&self.webdav.url)
+    }
+}
+
+impl fmt::Display for Webdav<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WebDAV folder {}", self.webdav.url)
+    }
+}
+
+/// The name an asset is uploaded as, eg "2024-05-04-<guid>.jpg", by the date
+/// it was added (in the default time zone).
+fn file_name(config: &Config, asset: &Asset, extension: &str) -> String {
+    let date = match asset.batch_date_created {
+        Some(date) => date
+            .with_timezone(&schedule::default_time_zone(config))
+            .format("%Y-%m-%d")
+            .to_string(),
+        None => "undated".to_string(),
+    };
+    format!(
+        "{}-{}.{}",
+        date,
+        utils::safe_file_name(&asset.guid.to_string()),
+        extension
+    )
+}
+
+// Not derived, to keep the password out of any debug output.
+impl fmt::Debug for Webdav<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webdav")
+            .field("webdav", self.webdav)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    #[test]
+    fn names() {
+        let config = testing::config(json!({}));
+        let mut photo = testing::photo("G1");
+        assert_eq!(file_name(&config, &photo, "jpg"), "2024-05-04-G1.jpg");
+
+        // The date is the local one (11pm UTC is the next day in summer).
+        photo.batch_date_created =
+            Some(Utc.with_ymd_and_hms(2024, 5, 4, 23, 30, 0).unwrap());
+        assert_eq!(file_name(&config, &photo, "jpg"), "2024-05-05-G1.jpg");
+
+        photo.batch_date_created = None;
+        assert_eq!(file_name(&config, &photo, "mp4"), "undated-G1.mp4");
+
+        // Nothing in a GUID can escape the folder.
+        photo.guid = testing::id("../G/1");
+        assert_eq!(file_name(&config, &photo, "jpg"), "undated-G1.jpg");
+    }
+}
@@ -0,0 +1,119 @@
+//! Generic webhook notifier: POST each update as JSON to a URL
+//!
+//! The body is the `notify::Update`, serialized as documented in the README.
+//! If there's a secret, then the body's HMAC-SHA256 goes in a header, so the
+//! receiver can check it came from us.
+
+use crate::notify::{Notifier, Update};
+use crate::state::State;
+use crate::types::*;
+use crate::utils;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::time::Duration;
+use surf::http::mime;
+use surf::StatusCode;
+
+/// Header carrying the signature, as "sha256=<hex HMAC of the body>".
+pub static SIGNATURE_HEADER: &str = "X-iCloud-Biff-Signature";
+
+/// How long to wait before the first retry (doubling each time after that).
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A configured webhook.
+pub struct Webhook<'a> {
+    config: &'a WebhookConfig,
+    secret: Option<Vec<u8>>,
+}
+
+/// Why a POST failed, and whether it's worth trying again.
+struct Failure {
+    message: String,
+    retryable: bool,
+}
+
+impl<'a> Webhook<'a> {
+    /// Set up a webhook, loading its secret (if any).
+    pub fn new(config: &'a WebhookConfig) -> Result<Self, std::io::Error> {
+        let secret = match &config.secret_file {
+            Some(fname) => Some(utils::load_secret(fname)?),
+            None => None,
+        };
+        Ok(Webhook { config, secret })
+    }
+
+    /// Make one attempt at POSTing the body.
+    fn post(&self, body: &[u8]) -> Result<(), Failure> {
+        let mut request = surf::post(&self.config.url)
+            .content_type(mime::JSON)
+            .body(body.to_vec());
+        if let Some(secret) = &self.secret {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+                .expect("HMAC can take a key of any size");
+            mac.update(body);
+            let signature = hex::encode(mac.finalize().into_bytes());
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", signature));
+        }
+
+        // Network errors, server errors and rate limiting might be temporary,
+        // but anything else means the request is wrong.
+        let response = smol::block_on(request.send()).map_err(|err| Failure {
+            message: err.to_string(),
+            retryable: true,
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(Failure {
+                message: format!("HTTP status {}", status),
+                retryable: status.is_server_error()
+                    || status == StatusCode::TooManyRequests,
+            })
+        }
+    }
+}
+
+impl Notifier for Webhook<'_> {
+    fn notify(
+        &self,
+        update: &Update<'_>,
+        _state: &mut State,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let body = serde_json::to_vec(update)?;
+        let mut delay = RETRY_DELAY;
+        for attempt in 0.. {
+            match self.post(&body) {
+                Ok(()) => break,
+                Err(failure) if failure.retryable && attempt < self.config.retries => {
+                    eprintln!(
+                        "Warning: {} failed ({}), retrying in {}s",
+                        self,
+                        failure.message,
+                        delay.as_secs()
+                    );
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(failure) => return Err(failure.message.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Webhook<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "webhook {}", self.config.url)
+    }
+}
+
+// Not derived, to keep the secret out of any debug output.
+impl fmt::Debug for Webhook<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("config", self.config)
+            .finish_non_exhaustive()
+    }
+}